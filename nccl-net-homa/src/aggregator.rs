@@ -0,0 +1,295 @@
+use crate::error::{Error, Result};
+use crate::reduce::{self, DataType, Op};
+use crate::wire::Chunk;
+use roma::consts::{HomaRecvmsgFlags, HOMA_MAX_PAYLOAD_LENGTH};
+use roma::HomaTransport;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long the first contribution to a chunk waits for the others by default.
+pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A chunk not every rank of its group contributed to yet.
+struct Partial {
+    /// The first contribution, every other must agree with it.
+    chunk: Chunk,
+    acc: Vec<u8>,
+    /// The requests of the contributing ranks, by rank, all are answered with the result.
+    contributors: BTreeMap<u32, (SocketAddr, u64)>,
+    /// When the contributors are refused if the chunk is still incomplete.
+    deadline: Instant,
+}
+
+/// Reduces the chunks the ranks of a group send it, see [`crate::collnet`].
+///
+/// Each chunk is a request whose response is held back until every rank of the group sent
+/// its chunk for the same reduction and offset, all of them are then answered with the
+/// reduced chunk.
+///
+/// A chunk that is refused takes the other contributions to its reduction down with it, and
+/// contributions still waiting for the rest of their group after `timeout` are refused as
+/// well, so a rank that fails does not leave its peers' chunks behind.
+pub struct Aggregator<T: HomaTransport> {
+    socket: T,
+    /// Holds a whole chunk, responses are shorter by its header and fit an RPC as well.
+    buf: Vec<u8>,
+    /// By group, sequence number and offset.
+    partials: HashMap<(u64, u32, u64), Partial>,
+    /// The keys of the partials in the order they expire, some may be complete already.
+    deadlines: VecDeque<(Instant, (u64, u32, u64))>,
+    timeout: Duration,
+}
+
+impl<T: HomaTransport> Aggregator<T> {
+    pub fn new(socket: T, timeout: Duration) -> Self {
+        Self {
+            socket,
+            buf: vec![0; HOMA_MAX_PAYLOAD_LENGTH],
+            partials: HashMap::new(),
+            deadlines: VecDeque::new(),
+            timeout,
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Serves chunks until the socket is shut down.
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            match self.poll(HomaRecvmsgFlags::empty()) {
+                Ok(_) => {}
+                Err(Error::Homa(roma::Error::System(err)))
+                    if err.raw_os_error() == Some(libc::ESHUTDOWN) =>
+                {
+                    return Ok(())
+                }
+                Err(err) => log::warn!("aggregator: failed to receive chunk: {}", err),
+            }
+        }
+    }
+
+    /// Receives one chunk, returns false if none was pending on a nonblocking receive.
+    ///
+    /// Partials past their deadline are refused first, the daemon holds on to them until it
+    /// is polled again.
+    pub fn poll(&mut self, flags: HomaRecvmsgFlags) -> Result<bool> {
+        self.expire(Instant::now());
+
+        let (length, addr, id, _) =
+            match self
+                .socket
+                .recv(&mut self.buf, flags | HomaRecvmsgFlags::REQUEST, 0)
+            {
+                Ok(result) => result,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => Err(err)?,
+            };
+
+        if let Err(err) = self.contribute(length, addr, id) {
+            log::warn!("aggregator: refusing chunk from {}: {}", addr, err);
+            self.refuse(addr, id);
+        }
+
+        Ok(true)
+    }
+
+    /// An empty response tells the rank that its chunk was refused.
+    fn refuse(&self, addr: SocketAddr, id: u64) {
+        if let Err(err) = self.socket.send(&[], addr, id, 0) {
+            log::warn!("aggregator: failed to respond to {}: {}", addr, err);
+        }
+    }
+
+    /// Drops the partials of reduction `seq` of `group`, refusing their contributors, none
+    /// of them can complete anymore.
+    fn abandon(&mut self, group: u64, seq: u32) {
+        let keys: Vec<_> = self
+            .partials
+            .keys()
+            .filter(|key| (key.0, key.1) == (group, seq))
+            .copied()
+            .collect();
+        for key in keys {
+            let partial = self.partials.remove(&key).unwrap();
+            for (addr, id) in partial.contributors.into_values() {
+                self.refuse(addr, id);
+            }
+        }
+    }
+
+    /// Refuses the contributors of the partials whose deadline passed before `now`.
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(deadline, key)) = self.deadlines.front() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop_front();
+
+            // The chunk may have completed, or been abandoned and started over since.
+            if self.partials.get(&key).map(|partial| partial.deadline) != Some(deadline) {
+                continue;
+            }
+            log::warn!(
+                "aggregator: reduction {} of group {:#x} timed out",
+                key.1,
+                key.0
+            );
+            self.abandon(key.0, key.1);
+        }
+    }
+
+    fn contribute(&mut self, length: usize, addr: SocketAddr, id: u64) -> Result<()> {
+        let (chunk, payload) = Chunk::decode(&self.buf[..length])?;
+        let data_type = DataType::try_from(chunk.data_type)?;
+        let op = Op::try_from(chunk.op)?;
+
+        if chunk.rank >= chunk.nranks || payload.len() % data_type.size() != 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let key = (chunk.group, chunk.seq, chunk.offset);
+        let partial = match self.partials.entry(key) {
+            Entry::Occupied(entry) => {
+                let partial = entry.into_mut();
+                let first = &partial.chunk;
+                if (first.nranks, first.data_type, first.op)
+                    != (chunk.nranks, chunk.data_type, chunk.op)
+                    || partial.acc.len() != payload.len()
+                    || partial.contributors.contains_key(&chunk.rank)
+                {
+                    self.abandon(chunk.group, chunk.seq);
+                    return Err(Error::InvalidUsage);
+                }
+                reduce::reduce(data_type, op, &mut partial.acc, payload);
+                partial
+            }
+            Entry::Vacant(entry) => {
+                let deadline = Instant::now() + self.timeout;
+                self.deadlines.push_back((deadline, key));
+                entry.insert(Partial {
+                    chunk,
+                    acc: payload.to_vec(),
+                    contributors: BTreeMap::new(),
+                    deadline,
+                })
+            }
+        };
+        partial.contributors.insert(chunk.rank, (addr, id));
+
+        if partial.contributors.len() == chunk.nranks as usize {
+            let partial = self.partials.remove(&key).unwrap();
+            for (addr, id) in partial.contributors.into_values() {
+                if let Err(err) = self.socket.send(&partial.acc, addr, id, 0) {
+                    log::warn!("aggregator: failed to respond to {}: {}", addr, err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::aggregator::*;
+    use nccl_net_sys::{ncclDataType_t, ncclRedOp_t};
+    use roma::EmulatedSocket;
+    use socket2::Domain;
+
+    fn aggregator(timeout: Duration) -> Aggregator<EmulatedSocket> {
+        let mut socket = EmulatedSocket::new(Domain::IPV4).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        Aggregator::new(socket, timeout)
+    }
+
+    /// Sends the chunk of `rank` at `offset` of a reduction of two ranks.
+    fn contribute(
+        socket: &EmulatedSocket,
+        aggregator: SocketAddr,
+        rank: u32,
+        offset: u64,
+        op: ncclRedOp_t,
+    ) -> u64 {
+        let header = Chunk {
+            group: 42,
+            offset,
+            seq: 0,
+            nranks: 2,
+            rank,
+            data_type: ncclDataType_t::ncclUint32.0,
+            op: op.0,
+        }
+        .encode();
+        let chunk = [&header[..], &[1, 0, 0, 0]].concat();
+        socket.send(&chunk, aggregator, 0, 0).unwrap()
+    }
+
+    fn response(socket: &mut EmulatedSocket, id: u64) -> Vec<u8> {
+        let mut buf = vec![0; 16];
+        let (length, ..) = socket
+            .recv(&mut buf, HomaRecvmsgFlags::empty(), id)
+            .unwrap();
+        buf[..length].to_vec()
+    }
+
+    fn drain(aggregator: &mut Aggregator<EmulatedSocket>) {
+        while aggregator.poll(HomaRecvmsgFlags::NONBLOCKING).unwrap() {}
+    }
+
+    #[test]
+    fn refused() {
+        let mut aggregator = aggregator(TIMEOUT);
+        let addr = aggregator.local_addr().unwrap();
+        let mut rank0 = EmulatedSocket::new(Domain::IPV4).unwrap();
+        let mut rank1 = EmulatedSocket::new(Domain::IPV4).unwrap();
+
+        let ids = [
+            contribute(&rank0, addr, 0, 0, ncclRedOp_t::ncclSum),
+            contribute(&rank0, addr, 0, 4, ncclRedOp_t::ncclSum),
+        ];
+        drain(&mut aggregator);
+        assert_eq!(aggregator.partials.len(), 2);
+
+        // The second rank disagrees on the op, every chunk of the reduction is refused.
+        let id = contribute(&rank1, addr, 1, 0, ncclRedOp_t::ncclMax);
+        drain(&mut aggregator);
+        assert!(aggregator.partials.is_empty());
+        assert!(response(&mut rank1, id).is_empty());
+        for id in ids {
+            assert!(response(&mut rank0, id).is_empty());
+        }
+    }
+
+    #[test]
+    fn expired() {
+        let mut aggregator = aggregator(Duration::from_millis(10));
+        let addr = aggregator.local_addr().unwrap();
+        let mut rank0 = EmulatedSocket::new(Domain::IPV4).unwrap();
+        let mut rank1 = EmulatedSocket::new(Domain::IPV4).unwrap();
+
+        let id = contribute(&rank0, addr, 0, 0, ncclRedOp_t::ncclSum);
+        drain(&mut aggregator);
+        assert_eq!(aggregator.partials.len(), 1);
+
+        std::thread::sleep(Duration::from_millis(20));
+        drain(&mut aggregator);
+        assert!(aggregator.partials.is_empty());
+        assert!(aggregator.deadlines.is_empty());
+        assert!(response(&mut rank0, id).is_empty());
+
+        // A late chunk starts over and completes within the deadline.
+        let ids = [
+            contribute(&rank0, addr, 0, 0, ncclRedOp_t::ncclSum),
+            contribute(&rank1, addr, 1, 0, ncclRedOp_t::ncclSum),
+        ];
+        drain(&mut aggregator);
+        assert!(aggregator.partials.is_empty());
+        assert_eq!(response(&mut rank0, ids[0]), [2, 0, 0, 0]);
+        assert_eq!(response(&mut rank1, ids[1]), [2, 0, 0, 0]);
+    }
+}
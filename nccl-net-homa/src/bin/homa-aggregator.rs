@@ -0,0 +1,52 @@
+//! Reduces the chunks of the ranks using the CollNet plugin, which find it through
+//! `NCCL_HOMA_COLLNET_ADDR`.
+//!
+//! `homa-aggregator <addr:port> [<timeout in seconds>]`, `NCCL_HOMA_PAGES` and
+//! `NCCL_HOMA_LOG_LEVEL` apply as for the plugin. Chunks still waiting for the rest of their
+//! group after the timeout, 60 seconds by default, are refused.
+
+use nccl_net_homa::aggregator::{self, Aggregator};
+use nccl_net_homa::config::Config;
+use roma::{HomaSocket, HomaTransport};
+use socket2::Domain;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+struct Stderr;
+
+impl log::Log for Stderr {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("{} {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let Some(addr) = std::env::args().nth(1) else {
+        eprintln!("usage: homa-aggregator <addr:port> [<timeout in seconds>]");
+        std::process::exit(2);
+    };
+    let addr: SocketAddr = addr.parse()?;
+    let timeout = match std::env::args().nth(2) {
+        Some(timeout) => Duration::from_secs(timeout.parse()?),
+        None => aggregator::TIMEOUT,
+    };
+
+    let config = Config::from_env()?;
+    log::set_logger(&Stderr)?;
+    log::set_max_level(config.log_level);
+
+    let mut socket = HomaSocket::new(Domain::for_address(addr), config.pages)?;
+    socket.bind(addr)?;
+
+    let mut aggregator = Aggregator::new(socket, timeout);
+    log::info!("aggregating on {}", aggregator.local_addr()?);
+    aggregator.run()?;
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Measures the round trip of roma RPCs by size and prints the tuner table derived from
+//! them, see `nccl_net_homa::tuner::derive`, for `NCCL_HOMA_TUNER_FILE`.
+//!
+//! `homa-tune serve <addr:port>` answers on one node and `homa-tune <addr:port>` measures
+//! from another, over the Homa kernel module. `NCCL_HOMA_PAGES` applies as for the plugin.
+
+use nccl_net_homa::config::Config;
+use nccl_net_homa::tuner;
+use roma::consts::HOMA_MAX_PAYLOAD_LENGTH;
+use roma::rpc::{Client, Server};
+use roma::{HomaSocket, HomaTransport};
+use socket2::Domain;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Round trips per size, the median is kept.
+const ROUNDS: usize = 1000;
+
+/// The smallest size measured, sizes double up to the largest RPC.
+const MIN_BYTES: usize = 64;
+
+/// Answers like the receiver of a fragment, with an empty response.
+fn serve<T: HomaTransport + Send + 'static>(socket: T) -> roma::Result<Server<T>> {
+    Server::spawn(socket, 1, |_: &[u8], _| vec![])
+}
+
+fn measure<T: HomaTransport>(client: &Client<T>, addr: SocketAddr) -> roma::Result<String> {
+    let mut samples = vec![];
+    let mut bytes = MIN_BYTES;
+    while bytes <= HOMA_MAX_PAYLOAD_LENGTH {
+        let request = vec![0u8; bytes];
+        for _ in 0..ROUNDS / 10 {
+            client.call(addr, &request)?;
+        }
+
+        let mut latencies: Vec<Duration> = (0..ROUNDS)
+            .map(|_| {
+                let start = Instant::now();
+                client.call(addr, &request).map(|_| start.elapsed())
+            })
+            .collect::<roma::Result<_>>()?;
+        latencies.sort();
+        samples.push((bytes, latencies[ROUNDS / 2]));
+
+        bytes *= 2;
+    }
+
+    let mut output = String::from("# bytes, median round trip in ns\n");
+    for (bytes, latency) in &samples {
+        output += &format!("# {}, {}\n", bytes, latency.as_nanos());
+    }
+    Ok(output + &tuner::derive(&samples))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = Config::from_env()?;
+
+    match &args[..] {
+        [mode, addr] if mode == "serve" => {
+            let addr: SocketAddr = addr.parse()?;
+            let mut socket = HomaSocket::new(Domain::for_address(addr), config.pages)?;
+            socket.bind(addr)?;
+            let server = serve(socket)?;
+            eprintln!("serving on {}", server.local_addr()?);
+            loop {
+                std::thread::park();
+            }
+        }
+        [addr] => {
+            let addr: SocketAddr = addr.parse()?;
+            let socket = HomaSocket::new(Domain::for_address(addr), config.pages)?;
+            print!("{}", measure(&Client::new(socket), addr)?);
+        }
+        _ => {
+            eprintln!("usage: homa-tune [serve] <addr:port>");
+            std::process::exit(2);
+        }
+    }
+
+    Ok(())
+}
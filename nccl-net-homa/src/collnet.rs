@@ -0,0 +1,545 @@
+//! An experimental CollNet plugin, reducing on the CPU of an aggregator, see
+//! [`crate::aggregator`].
+//!
+//! Each rank sends its input to the aggregator in chunks, one RPC each, the responses carry
+//! the reduced chunks. The ranks of a comm are told apart from other groups by the nonce in
+//! the handle of rank 0.
+
+use crate::config;
+use crate::error::{Error, Result};
+use crate::homa::{port, Homa, Port};
+use crate::memory::{self, InUse, MemoryHandle};
+use crate::reduce::{DataType, Op};
+use crate::wire::Chunk;
+use nccl_net_sys::collnet::{CollNetPlugin, Reduction};
+use nccl_net_sys::plugin::NetPlugin;
+use nccl_net_sys::*;
+use roma::consts::{HomaRecvmsgFlags, HOMA_MAX_PAYLOAD_LENGTH};
+use std::{
+    ffi::c_void,
+    io::{ErrorKind, IoSlice},
+    net::SocketAddr,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
+};
+
+pub struct ListenComm {
+    dev: i32,
+}
+
+pub struct CollComm {
+    port: Arc<Mutex<Port>>,
+    aggregator: SocketAddr,
+    group: u64,
+    rank: u32,
+    nranks: u32,
+    seq: u32,
+    /// Reductions posted and not yet completed, at most `window` of them.
+    inflight: Arc<AtomicUsize>,
+    window: usize,
+}
+
+impl CollComm {
+    fn new(dev: i32, aggregator: SocketAddr, group: u64, rank: u32, nranks: u32) -> Result<Self> {
+        Ok(Self {
+            port: port(dev)?,
+            aggregator,
+            group,
+            rank,
+            nranks,
+            seq: 0,
+            inflight: Arc::default(),
+            window: config::get().window,
+        })
+    }
+}
+
+/// A reduction holding a slot of its comm's window until dropped.
+pub struct Request<'a> {
+    port: Arc<Mutex<Port>>,
+    aggregator: SocketAddr,
+    /// The count of its comm, which may be closed first.
+    inflight: Arc<AtomicUsize>,
+    recv: &'a mut [u8],
+    /// The RPCs of the chunks whose result has not arrived yet, with where it goes.
+    chunks: Vec<(u64, Range<usize>)>,
+    /// Keeps `deregMr` of the handles refused until the request is dropped.
+    _mhandles: Vec<InUse<'a>>,
+}
+
+impl<'a> Drop for Request<'a> {
+    fn drop(&mut self) {
+        if !self.chunks.is_empty() {
+            // After a panic under the lock the socket is dropped with its RPCs anyway.
+            if let Ok(port) = self.port.lock() {
+                abort(&port, &mut self.chunks);
+            }
+        }
+        self.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Aborts the RPCs of the chunks still outstanding, their results are not wanted anymore.
+fn abort(port: &Port, chunks: &mut Vec<(u64, Range<usize>)>) {
+    for (id, _) in chunks.drain(..) {
+        let _ = port.socket.abort(id, 0);
+    }
+}
+
+pub struct HomaCollNet {}
+
+impl CollNetPlugin for HomaCollNet {
+    type ListenComm = ListenComm;
+    type CollComm = CollComm;
+    type Request<'a> = Request<'a>;
+    type MemoryHandle = MemoryHandle;
+    type Error = Error;
+
+    fn init(logger: ncclDebugLogger_t) -> Result<()> {
+        Homa::init(logger)
+    }
+
+    /// The devices of the point-to-point plugin, none unless an aggregator is configured.
+    fn devices() -> Result<i32> {
+        if config::get().collnet.is_none() {
+            return Ok(0);
+        }
+        Homa::devices()
+    }
+
+    fn get_properties(dev: i32) -> Result<ncclNetProperties_v6_t> {
+        Homa::get_properties(dev)
+    }
+
+    fn listen(dev: i32, handle: &mut [u8]) -> Result<ListenComm> {
+        handle.fill(0);
+        handle[..8].copy_from_slice(&rand::random::<u64>().to_le_bytes());
+        Ok(ListenComm { dev })
+    }
+
+    fn connect(handles: &[&[u8]], rank: usize, listen_comm: &mut ListenComm) -> Result<CollComm> {
+        let aggregator = config::get().collnet.ok_or(Error::InvalidUsage)?;
+        let group = u64::from_le_bytes(handles[0][..8].try_into().unwrap());
+
+        CollComm::new(
+            listen_comm.dev,
+            aggregator,
+            group,
+            rank.try_into().map_err(|_| Error::InvalidArgument)?,
+            handles
+                .len()
+                .try_into()
+                .map_err(|_| Error::InvalidArgument)?,
+        )
+    }
+
+    fn reduce_support(data_type: ncclDataType_t, op: ncclRedOp_t) -> Result<bool> {
+        Ok(DataType::try_from(data_type).is_ok() && Op::try_from(op).is_ok())
+    }
+
+    /// Registrations are not tied to the comm, see [`memory`].
+    fn reg_mr(
+        _comm: &mut CollComm,
+        data: *mut c_void,
+        size: usize,
+        type_: i32,
+    ) -> Result<MemoryHandle> {
+        memory::register(data as usize, size, type_)
+    }
+
+    fn dereg_mr(_comm: &mut CollComm, mhandle: &MemoryHandle) -> Result<()> {
+        memory::deregister(mhandle)
+    }
+
+    fn iallreduce<'a>(
+        comm: &mut CollComm,
+        reduction: Reduction<'a, MemoryHandle>,
+    ) -> Result<Option<Request<'a>>> {
+        if comm.inflight.load(Ordering::Relaxed) == comm.window {
+            return Ok(None);
+        }
+
+        let data_type = DataType::try_from(reduction.data_type)?;
+        let op = Op::try_from(reduction.op)?;
+
+        // Chunks hold whole elements, the aggregator reduces each on its own.
+        let max = config::get()
+            .max_message_size
+            .min(HOMA_MAX_PAYLOAD_LENGTH - Chunk::LEN);
+        let step = (max / data_type.size()).max(1) * data_type.size();
+
+        let recv = reduction.recv;
+        let mhandles = [
+            (reduction.send_mhandle, reduction.send.unwrap_or(recv)),
+            (reduction.recv_mhandle, recv),
+        ]
+        .into_iter()
+        .filter_map(|(mhandle, data)| mhandle.map(|mhandle| mhandle.acquire(data)))
+        .collect::<Result<_>>()?;
+
+        let input = reduction.send.unwrap_or(recv);
+        let port = comm.port.lock()?;
+
+        let mut chunks = vec![];
+        for offset in (0..input.len()).step_by(step) {
+            let range = offset..input.len().min(offset + step);
+
+            let header = Chunk {
+                group: comm.group,
+                offset: offset as u64,
+                seq: comm.seq,
+                nranks: comm.nranks,
+                rank: comm.rank,
+                data_type: data_type as u32,
+                op: op as u32,
+            }
+            .encode();
+
+            let id = port.socket.send_vectored(
+                &[IoSlice::new(&header), IoSlice::new(&input[range.clone()])],
+                comm.aggregator,
+                0,
+                0,
+            );
+
+            match id {
+                Ok(id) => chunks.push((id, range)),
+                Err(err) => {
+                    abort(&port, &mut chunks);
+                    return Err(err)?;
+                }
+            }
+        }
+
+        drop(port);
+
+        comm.inflight.fetch_add(1, Ordering::Relaxed);
+        comm.seq = comm.seq.wrapping_add(1);
+
+        Ok(Some(Request {
+            port: comm.port.clone(),
+            aggregator: comm.aggregator,
+            inflight: comm.inflight.clone(),
+            recv,
+            chunks,
+            _mhandles: mhandles,
+        }))
+    }
+
+    fn test(request: &mut Request<'_>) -> Result<Option<usize>> {
+        let mut port = request.port.lock()?;
+
+        while let Some((id, range)) = request.chunks.last().cloned() {
+            let result = port.socket.recv(
+                &mut request.recv[range.clone()],
+                HomaRecvmsgFlags::NONBLOCKING,
+                id,
+            );
+
+            let err = match result {
+                Ok((length, ..)) if length == range.len() => {
+                    request.chunks.pop();
+                    continue;
+                }
+                Ok(_) => {
+                    log::warn!("aggregator {} refused a chunk", request.aggregator);
+                    Error::InvalidUsage
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => err.into(),
+            };
+
+            // The reduction cannot complete anymore.
+            abort(&port, &mut request.chunks);
+            return Err(err);
+        }
+
+        Ok(Some(request.recv.len()))
+    }
+
+    fn close_coll(_comm: CollComm) -> Result<()> {
+        Ok(())
+    }
+
+    fn close_listen(_listen_comm: ListenComm) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::aggregator::{self, Aggregator};
+    use crate::collnet::*;
+    use crate::device;
+    use roma::{EmulatedSocket, HomaTransport};
+    use socket2::Domain;
+    use std::thread::{self, JoinHandle};
+
+    /// Runs an aggregator on the interface of device 0, returning a handle to shut it down
+    /// and the comms of a group of `nranks`.
+    fn group(nranks: u32) -> (EmulatedSocket, JoinHandle<()>, Vec<CollComm>) {
+        HomaCollNet::init(None).unwrap();
+
+        let addr = SocketAddr::new(device::with(0, |device| device.addr).unwrap(), 0);
+        let mut socket = EmulatedSocket::new(Domain::for_address(addr)).unwrap();
+        socket.bind(addr).unwrap();
+        let shutdown = socket.try_clone().unwrap();
+
+        let mut aggregator = Aggregator::new(socket, aggregator::TIMEOUT);
+        let addr = aggregator.local_addr().unwrap();
+        let server = thread::spawn(move || aggregator.run().unwrap());
+
+        let group = rand::random();
+        let comms = (0..nranks)
+            .map(|rank| CollComm::new(0, addr, group, rank, nranks).unwrap())
+            .collect();
+
+        (shutdown, server, comms)
+    }
+
+    fn wait(request: &mut Request) -> Result<usize> {
+        loop {
+            if let Some(size) = HomaCollNet::test(request)? {
+                return Ok(size);
+            }
+        }
+    }
+
+    #[test]
+    fn allreduce() {
+        let (shutdown, server, mut comms) = group(3);
+
+        // Larger than an RPC, reduced in several chunks.
+        let count = 300_000;
+        let inputs: Vec<Vec<u8>> = (1..=3)
+            .map(|rank| {
+                (0..count)
+                    .flat_map(|i| ((i * rank) as f32).to_ne_bytes())
+                    .collect()
+            })
+            .collect();
+        let mut outputs = vec![vec![0u8; count * 4]; 3];
+
+        let mut requests: Vec<_> = comms
+            .iter_mut()
+            .zip(&inputs)
+            .zip(&mut outputs)
+            .map(|((comm, input), output)| {
+                let reduction = Reduction {
+                    send: Some(&input[..]),
+                    recv: &mut output[..],
+                    count,
+                    data_type: ncclDataType_t::ncclFloat32,
+                    op: ncclRedOp_t::ncclSum,
+                    send_mhandle: None,
+                    recv_mhandle: None,
+                };
+                HomaCollNet::iallreduce(comm, reduction).unwrap().unwrap()
+            })
+            .collect();
+
+        for request in &mut requests {
+            assert_eq!(wait(request).unwrap(), count * 4);
+        }
+        drop(requests);
+
+        let expected: Vec<u8> = (0..count)
+            .flat_map(|i| ((i * 6) as f32).to_ne_bytes())
+            .collect();
+        for output in &outputs {
+            assert!(output == &expected);
+        }
+
+        // In place, the input is read before the result lands.
+        let mut buffers = [[3i32, -7], [5, -9], [0, 0]].map(|values| {
+            values
+                .iter()
+                .flat_map(|v| v.to_ne_bytes())
+                .collect::<Vec<u8>>()
+        });
+        let mut requests: Vec<_> = comms
+            .iter_mut()
+            .zip(&mut buffers)
+            .map(|(comm, buffer)| {
+                let reduction = Reduction {
+                    send: None,
+                    recv: &mut buffer[..],
+                    count: 2,
+                    data_type: ncclDataType_t::ncclInt32,
+                    op: ncclRedOp_t::ncclMax,
+                    send_mhandle: None,
+                    recv_mhandle: None,
+                };
+                HomaCollNet::iallreduce(comm, reduction).unwrap().unwrap()
+            })
+            .collect();
+
+        for request in &mut requests {
+            wait(request).unwrap();
+        }
+        drop(requests);
+
+        let expected: Vec<u8> = [5i32, 0].iter().flat_map(|v| v.to_ne_bytes()).collect();
+        for buffer in &buffers {
+            assert_eq!(*buffer, expected);
+        }
+
+        shutdown.shutdown().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn mismatch() {
+        let (shutdown, server, mut comms) = group(2);
+
+        let mut outputs = [[0u8; 4]; 2];
+        let mut requests: Vec<_> = comms
+            .iter_mut()
+            .zip(&mut outputs)
+            .zip([ncclRedOp_t::ncclSum, ncclRedOp_t::ncclMin])
+            .map(|((comm, output), op)| {
+                let reduction = Reduction {
+                    send: Some(&[0u8; 4][..]),
+                    recv: &mut output[..],
+                    count: 1,
+                    data_type: ncclDataType_t::ncclUint32,
+                    op,
+                    send_mhandle: None,
+                    recv_mhandle: None,
+                };
+                HomaCollNet::iallreduce(comm, reduction).unwrap().unwrap()
+            })
+            .collect();
+
+        // Requests outlive their comms.
+        for comm in comms {
+            HomaCollNet::close_coll(comm).unwrap();
+        }
+
+        // The second rank's chunk disagrees with the first one's op, the first one is
+        // refused along with it.
+        assert!(matches!(wait(&mut requests[1]), Err(Error::InvalidUsage)));
+        assert!(matches!(wait(&mut requests[0]), Err(Error::InvalidUsage)));
+
+        drop(requests);
+        shutdown.shutdown().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn dropped() {
+        let (shutdown, server, mut comms) = group(2);
+        let comm = &mut comms[0];
+
+        fn reduce<'a>(comm: &mut CollComm, output: &'a mut [u8]) -> Option<Request<'a>> {
+            let reduction = Reduction {
+                send: None,
+                recv: output,
+                count: 1,
+                data_type: ncclDataType_t::ncclUint32,
+                op: ncclRedOp_t::ncclSum,
+                send_mhandle: None,
+                recv_mhandle: None,
+            };
+            HomaCollNet::iallreduce(comm, reduction).unwrap()
+        }
+
+        // The other rank never contributes, the window fills up.
+        let mut outputs = vec![[0u8; 4]; comm.window + 1];
+        let (last, outputs) = outputs.split_last_mut().unwrap();
+        let requests: Vec<_> = outputs
+            .iter_mut()
+            .map(|output| reduce(comm, output).unwrap())
+            .collect();
+        assert!(reduce(comm, last).is_none());
+
+        // Requests dropped unfinished give their slot back.
+        drop(requests);
+        assert!(reduce(comm, last).is_some());
+
+        shutdown.shutdown().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn registered() {
+        let (shutdown, server, mut comms) = group(2);
+
+        let inputs = [[1u8, 0, 0, 0], [2, 0, 0, 0]];
+        let mut outputs = [[0u8; 8]; 2];
+        let mhandles: Vec<_> = comms
+            .iter_mut()
+            .zip(&inputs)
+            .zip(&mut outputs)
+            .map(|((comm, input), output)| {
+                let register = |comm: &mut CollComm, data: &[u8]| {
+                    let data = data.as_ptr().cast_mut().cast();
+                    HomaCollNet::reg_mr(comm, data, 4, NCCL_PTR_HOST as i32).unwrap()
+                };
+                (register(comm, input), register(comm, output))
+            })
+            .collect();
+
+        let mut requests = vec![];
+        for (((comm, input), output), (send_mhandle, recv_mhandle)) in comms
+            .iter_mut()
+            .zip(&inputs)
+            .zip(&mut outputs)
+            .zip(&mhandles)
+        {
+            let reduction = Reduction {
+                send: Some(&input[..]),
+                recv: &mut output[..4],
+                count: 1,
+                data_type: ncclDataType_t::ncclUint32,
+                op: ncclRedOp_t::ncclSum,
+                send_mhandle: Some(send_mhandle),
+                recv_mhandle: Some(recv_mhandle),
+            };
+            requests.push(HomaCollNet::iallreduce(comm, reduction).unwrap().unwrap());
+        }
+
+        // Not while the reductions are using them.
+        for (send_mhandle, recv_mhandle) in &mhandles {
+            for mhandle in [send_mhandle, recv_mhandle] {
+                assert!(matches!(
+                    HomaCollNet::dereg_mr(&mut comms[0], mhandle),
+                    Err(Error::InvalidUsage)
+                ));
+            }
+        }
+
+        for request in &mut requests {
+            assert_eq!(wait(request).unwrap(), 4);
+        }
+        drop(requests);
+
+        for (send_mhandle, recv_mhandle) in &mhandles {
+            HomaCollNet::dereg_mr(&mut comms[0], send_mhandle).unwrap();
+            HomaCollNet::dereg_mr(&mut comms[0], recv_mhandle).unwrap();
+        }
+        for output in &outputs {
+            assert_eq!(*output, [3, 0, 0, 0, 0, 0, 0, 0]);
+        }
+
+        // Only within the registered range.
+        let (_, recv_mhandle) = &mhandles[0];
+        let reduction = Reduction {
+            send: None,
+            recv: &mut outputs[0][..],
+            count: 2,
+            data_type: ncclDataType_t::ncclUint32,
+            op: ncclRedOp_t::ncclSum,
+            send_mhandle: None,
+            recv_mhandle: Some(recv_mhandle),
+        };
+        assert!(matches!(
+            HomaCollNet::iallreduce(&mut comms[0], reduction),
+            Err(Error::InvalidArgument)
+        ));
+
+        shutdown.shutdown().unwrap();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+use crate::error::{Error, Result};
+use crate::wire::Header;
+use log::LevelFilter;
+use nccl_net_sys::NCCL_NET_MAX_REQUESTS;
+use roma::consts::HOMA_MAX_PAYLOAD_LENGTH;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+
+/// The largest `NCCL_HOMA_MAX_MSG_SIZE`, a fragment and its header fill a whole RPC.
+pub const MAX_MESSAGE_SIZE: usize = HOMA_MAX_PAYLOAD_LENGTH - Header::LEN;
+
+/// The configuration parsed by [`init`].
+static CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+/// Selects interfaces by name, with the syntax of `NCCL_SOCKET_IFNAME`.
+///
+/// A comma separated list of name prefixes, `=` in front of the list matches exact names
+/// instead and `^` in front of it excludes the listed interfaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IfnameFilter {
+    names: Vec<String>,
+    exact: bool,
+    exclude: bool,
+}
+
+impl IfnameFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        let listed = self.names.iter().any(|prefix| {
+            if self.exact {
+                name == prefix
+            } else {
+                name.starts_with(prefix.as_str())
+            }
+        });
+        listed != self.exclude
+    }
+}
+
+impl FromStr for IfnameFilter {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        let (exclude, s) = match s.strip_prefix('^') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let (exact, s) = match s.strip_prefix('=') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+
+        let names: Vec<String> = s
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        if names.is_empty() {
+            return Err(());
+        }
+
+        Ok(Self {
+            names,
+            exact,
+            exclude,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl FromStr for Family {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "AF_INET" => Ok(Family::V4),
+            "AF_INET6" => Ok(Family::V6),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `NCCL_HOMA_IFNAME`, interfaces to expose as devices.
+    pub ifname: Option<IfnameFilter>,
+    /// `NCCL_HOMA_FAMILY`, `AF_INET` or `AF_INET6`.
+    pub family: Family,
+    /// `NCCL_HOMA_PAGES`, bpages in the buffer region of the socket of each device.
+    pub pages: usize,
+    /// `NCCL_HOMA_MAX_MSG_SIZE`, the largest fragment, larger messages are split into several.
+    pub max_message_size: usize,
+    /// `NCCL_HOMA_WINDOW`, sends in flight per connection, at most the
+    /// `NCCL_NET_MAX_REQUESTS` NCCL ever posts.
+    pub window: usize,
+    /// `NCCL_HOMA_LOG_LEVEL`, e.g. `info` or `debug`.
+    pub log_level: LevelFilter,
+    /// `NCCL_HOMA_COLLNET_ADDR`, the aggregator reducing for the CollNet plugin, which has
+    /// no devices without one.
+    pub collnet: Option<SocketAddr>,
+    /// `NCCL_HOMA_TUNER_FILE`, the table of the tuner plugin, which uses its defaults
+    /// without one.
+    pub tuner: Option<PathBuf>,
+    /// `NCCL_HOMA_MLOCK=1` locks registered memory into RAM.
+    pub mlock: bool,
+    /// `NCCL_HOMA_EMULATE=1` runs over roma's in-process emulator instead of the Homa
+    /// kernel module.
+    pub emulate: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ifname: None,
+            family: Family::V4,
+            pages: 20000,
+            max_message_size: MAX_MESSAGE_SIZE,
+            window: NCCL_NET_MAX_REQUESTS as usize,
+            log_level: LevelFilter::Debug,
+            collnet: None,
+            tuner: None,
+            mlock: false,
+            emulate: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        Self::parse(|name| std::env::var(name).ok())
+    }
+
+    fn parse(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let default = Self::default();
+
+        let config = Self {
+            ifname: parse_var(&var, "NCCL_HOMA_IFNAME")?,
+            family: parse_var(&var, "NCCL_HOMA_FAMILY")?.unwrap_or(default.family),
+            pages: parse_var(&var, "NCCL_HOMA_PAGES")?.unwrap_or(default.pages),
+            max_message_size: parse_var(&var, "NCCL_HOMA_MAX_MSG_SIZE")?
+                .unwrap_or(default.max_message_size),
+            window: parse_var(&var, "NCCL_HOMA_WINDOW")?.unwrap_or(default.window),
+            log_level: parse_var(&var, "NCCL_HOMA_LOG_LEVEL")?.unwrap_or(default.log_level),
+            collnet: parse_var(&var, "NCCL_HOMA_COLLNET_ADDR")?,
+            tuner: parse_var(&var, "NCCL_HOMA_TUNER_FILE")?,
+            mlock: var("NCCL_HOMA_MLOCK").is_some_and(|v| v == "1"),
+            emulate: var("NCCL_HOMA_EMULATE").is_some_and(|v| v == "1"),
+        };
+
+        if config.pages == 0 {
+            log::warn!("NCCL_HOMA_PAGES must not be 0");
+            return Err(Error::InvalidArgument);
+        }
+
+        if config.window == 0 || config.window > NCCL_NET_MAX_REQUESTS as usize {
+            log::warn!(
+                "NCCL_HOMA_WINDOW must be between 1 and {}",
+                NCCL_NET_MAX_REQUESTS
+            );
+            return Err(Error::InvalidArgument);
+        }
+
+        if config.max_message_size == 0 || config.max_message_size > MAX_MESSAGE_SIZE {
+            log::warn!(
+                "NCCL_HOMA_MAX_MSG_SIZE must be between 1 and {}",
+                MAX_MESSAGE_SIZE
+            );
+            return Err(Error::InvalidArgument);
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_var<T: FromStr>(var: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>> {
+    match var(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            log::warn!("invalid {}: {:?}", name, value);
+            Error::InvalidArgument
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Parses the configuration from the environment, once per process.
+pub fn init() -> Result<()> {
+    let mut guard = CONFIG.lock()?;
+
+    if guard.is_none() {
+        let config = Config::from_env()?;
+        log::info!("{:?}", config);
+        *guard = Some(config);
+    }
+
+    Ok(())
+}
+
+/// The configuration parsed by [`init`], or the defaults before it ran.
+pub fn get() -> Config {
+    // The configuration is only ever replaced as a whole, a panic cannot leave it torn.
+    CONFIG
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::*;
+
+    fn parse(vars: &[(&str, &str)]) -> Result<Config> {
+        Config::parse(|name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn parse_env() {
+        let config = parse(&[
+            ("NCCL_HOMA_IFNAME", "^=eth0,eth1"),
+            ("NCCL_HOMA_FAMILY", "AF_INET6"),
+            ("NCCL_HOMA_PAGES", "100"),
+            ("NCCL_HOMA_WINDOW", "4"),
+            ("NCCL_HOMA_LOG_LEVEL", "warn"),
+            ("NCCL_HOMA_COLLNET_ADDR", "10.0.0.1:4000"),
+            ("NCCL_HOMA_TUNER_FILE", "/etc/homa-tuner.conf"),
+            ("NCCL_HOMA_MLOCK", "1"),
+        ])
+        .unwrap();
+
+        let ifname = config.ifname.unwrap();
+        assert!(!ifname.matches("eth0"));
+        assert!(ifname.matches("eth10"));
+        assert_eq!(config.family, Family::V6);
+        assert_eq!(config.pages, 100);
+        assert_eq!(config.window, 4);
+        assert_eq!(config.max_message_size, MAX_MESSAGE_SIZE);
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.collnet, Some("10.0.0.1:4000".parse().unwrap()));
+        assert_eq!(config.tuner, Some(PathBuf::from("/etc/homa-tuner.conf")));
+        assert!(config.mlock);
+
+        let ifname: IfnameFilter = "ib,eth".parse().unwrap();
+        assert!(ifname.matches("ib0"));
+        assert!(!ifname.matches("lo"));
+
+        // The largest fragment fits an RPC along with its header and roma's tag byte.
+        let max = MAX_MESSAGE_SIZE.to_string();
+        let config = parse(&[("NCCL_HOMA_MAX_MSG_SIZE", max.as_str())]).unwrap();
+        assert_eq!(
+            config.max_message_size + Header::LEN + 1,
+            roma::consts::HOMA_MAX_MESSAGE_LENGTH
+        );
+        let over = (MAX_MESSAGE_SIZE + 1).to_string();
+        assert!(matches!(
+            parse(&[("NCCL_HOMA_MAX_MSG_SIZE", over.as_str())]),
+            Err(Error::InvalidArgument)
+        ));
+
+        for vars in [
+            [("NCCL_HOMA_PAGES", "0")],
+            [("NCCL_HOMA_PAGES", "many")],
+            [("NCCL_HOMA_WINDOW", "0")],
+            [("NCCL_HOMA_WINDOW", "9")],
+            [("NCCL_HOMA_FAMILY", "AF_UNIX")],
+            [("NCCL_HOMA_IFNAME", "^")],
+            [("NCCL_HOMA_MAX_MSG_SIZE", "2000000")],
+            [("NCCL_HOMA_LOG_LEVEL", "loud")],
+            [("NCCL_HOMA_COLLNET_ADDR", "10.0.0.1")],
+        ] {
+            assert!(matches!(parse(&vars), Err(Error::InvalidArgument)));
+        }
+    }
+}
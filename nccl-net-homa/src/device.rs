@@ -0,0 +1,162 @@
+use crate::config::{self, Family};
+use crate::error::{Error, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::CString;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Reported when sysfs does not know the link speed, e.g. for virtual interfaces.
+const DEFAULT_SPEED: i32 = 10000;
+
+/// The devices found by [`init`], indexed by NCCL device number.
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+/// A network interface exposed to NCCL as a device.
+#[derive(Debug)]
+pub struct Device {
+    pub name: CString,
+    pub addr: IpAddr,
+    /// Link speed in Mbps.
+    pub speed: i32,
+    /// The resolved sysfs path of the backing PCI device, if any.
+    pub pci_path: Option<CString>,
+    pub guid: u64,
+}
+
+impl Device {
+    fn new(name: &str, addr: IpAddr) -> Self {
+        let sysfs = Path::new("/sys/class/net").join(name);
+
+        let speed = std::fs::read_to_string(sysfs.join("speed"))
+            .ok()
+            .and_then(|speed| speed.trim().parse().ok())
+            .filter(|speed| *speed > 0)
+            .unwrap_or_else(|| {
+                log::info!("device {}: unknown speed, assuming {}", name, DEFAULT_SPEED);
+                DEFAULT_SPEED
+            });
+
+        let pci_path = std::fs::canonicalize(sysfs.join("device"))
+            .ok()
+            .map(|path| CString::new(path.as_os_str().as_bytes()).unwrap());
+
+        Self {
+            name: CString::new(name).unwrap(),
+            addr,
+            speed,
+            pci_path,
+            guid: guid(name, &sysfs),
+        }
+    }
+}
+
+/// Derives the guid from the hardware address, falling back to the interface name.
+fn guid(name: &str, sysfs: &Path) -> u64 {
+    let mac = std::fs::read_to_string(sysfs.join("address"))
+        .ok()
+        .and_then(|address| {
+            address
+                .trim()
+                .split(':')
+                .map(|byte| u8::from_str_radix(byte, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+        })
+        .filter(|mac| mac.len() <= 8 && mac.iter().any(|byte| *byte != 0));
+
+    match mac {
+        Some(mac) => mac
+            .iter()
+            .fold(0, |guid, byte| (guid << 8) | u64::from(*byte)),
+        None => {
+            let mut hasher = DefaultHasher::new();
+            name.hash(&mut hasher);
+            hasher.finish()
+        }
+    }
+}
+
+/// Enumerates the interfaces carrying an address of the configured family, one device per
+/// interface, ordered by name. Loopback interfaces are only used if selected explicitly by
+/// `NCCL_HOMA_IFNAME`, or as a fallback if nothing else is found and no filter is set. A
+/// filter matching no usable interface fails with [`Error::InvalidArgument`].
+///
+/// Devices are enumerated only once, the names handed out to NCCL must stay valid.
+pub fn init() -> Result<()> {
+    let mut guard = DEVICES.lock()?;
+
+    if !guard.is_empty() {
+        return Ok(());
+    }
+
+    let config = config::get();
+
+    let mut interfaces = if_addrs::get_if_addrs()?;
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut devices: Vec<Device> = vec![];
+    for interface in interfaces {
+        let usable = match config.family {
+            Family::V4 => interface.ip().is_ipv4(),
+            // Link local addresses would need a scope id in the handle.
+            Family::V6 => interface.ip().is_ipv6() && !interface.is_link_local(),
+        };
+        let selected = match &config.ifname {
+            Some(ifname) => ifname.matches(&interface.name),
+            None => !interface.is_loopback(),
+        };
+        if !usable || !selected {
+            continue;
+        }
+        if devices
+            .iter()
+            .any(|device| device.name.to_bytes() == interface.name.as_bytes())
+        {
+            continue;
+        }
+        devices.push(Device::new(&interface.name, interface.ip()));
+    }
+
+    if devices.is_empty() {
+        if config.ifname.is_some() {
+            log::warn!("no usable interface matches NCCL_HOMA_IFNAME");
+            return Err(Error::InvalidArgument);
+        }
+        log::warn!("no usable interface found, falling back to loopback");
+        let addr = match config.family {
+            Family::V4 => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            Family::V6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        };
+        devices.push(Device::new("lo", addr));
+    }
+
+    for (dev, device) in devices.iter().enumerate() {
+        log::info!(
+            "device {}: {:?} {} {} Mbps",
+            dev,
+            device.name,
+            device.addr,
+            device.speed
+        );
+    }
+
+    *guard = devices;
+
+    Ok(())
+}
+
+pub fn count() -> Result<usize> {
+    Ok(DEVICES.lock()?.len())
+}
+
+/// Runs `f` on device `dev`, failing with [`Error::InvalidArgument`] if there is none.
+pub fn with<T>(dev: i32, f: impl FnOnce(&Device) -> T) -> Result<T> {
+    let devices = DEVICES.lock()?;
+    let device = usize::try_from(dev)
+        .ok()
+        .and_then(|dev| devices.get(dev))
+        .ok_or(Error::InvalidArgument)?;
+    Ok(f(device))
+}
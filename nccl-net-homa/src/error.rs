@@ -1,9 +1,12 @@
 use nccl_net_sys::ncclResult_t;
+use std::sync::PoisonError;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("system")]
+    #[error(transparent)]
     System(#[from] std::io::Error),
+    #[error(transparent)]
+    Homa(#[from] roma::Error),
     #[error("internal")]
     Internal,
     #[error("invalid usage")]
@@ -16,6 +19,16 @@ impl From<Error> for ncclResult_t {
     fn from(val: Error) -> Self {
         match val {
             Error::System(_) => ncclResult_t::ncclSystemError,
+            Error::Homa(err) => match err {
+                roma::Error::Aborted { .. } | roma::Error::TimedOut { .. } => {
+                    ncclResult_t::ncclRemoteError
+                }
+                roma::Error::Truncated { .. } => ncclResult_t::ncclInvalidUsage,
+                roma::Error::UnknownRpc { .. } => ncclResult_t::ncclInternalError,
+                roma::Error::ModuleMissing | roma::Error::System(_) => {
+                    ncclResult_t::ncclSystemError
+                }
+            },
             Error::Internal => ncclResult_t::ncclInternalError,
             Error::InvalidUsage => ncclResult_t::ncclInvalidUsage,
             Error::InvalidArgument => ncclResult_t::ncclInvalidArgument,
@@ -23,4 +36,12 @@ impl From<Error> for ncclResult_t {
     }
 }
 
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Self {
+        // A panic was caught while the lock was held, what it guards may be torn.
+        log::warn!("lock poisoned by an earlier panic");
+        Error::Internal
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
@@ -1,208 +1,615 @@
 use crate::error::{Error, Result};
+use crate::matcher::Matcher;
+use crate::memory::{self, InUse, MemoryHandle};
+use crate::wire::{Handle, Header, Kind};
+use crate::{config, device};
 use log::LevelFilter;
+use nccl_net_sys::plugin::{Buffer, Comm, NetPlugin};
 use nccl_net_sys::*;
-use roma::{consts::HomaRecvmsgFlags, HomaSocket};
+use roma::{
+    consts::{HomaRecvmsgFlags, HOMA_MAX_MESSAGE_LENGTH},
+    EmulatedSocket, HomaSocket, HomaTransport,
+};
 use socket2::Domain;
 use std::{
-    ffi::{c_int, CStr, CString},
-    io::ErrorKind,
-    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    collections::{BTreeMap, HashMap, VecDeque},
+    ffi::c_void,
+    io::{ErrorKind, IoSlice},
+    marker::PhantomData,
+    net::SocketAddr,
+    ops::Range,
     ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Mutex, Weak},
 };
 
-pub enum Request<'a, 'b> {
+/// The most receives NCCL may group into one `irecv`.
+const MAX_RECVS: usize = 8;
+
+/// The tail of the handle, where `connect` keeps its progress between calls.
+const STAGE: Range<usize> = NCCL_NET_HANDLE_MAXSIZE as usize - 8..NCCL_NET_HANDLE_MAXSIZE as usize;
+
+type Socket = Box<dyn HomaTransport + Send>;
+
+/// The port of every device with live comms, by device number.
+static PORTS: Mutex<BTreeMap<i32, Weak<Mutex<Port>>>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn socket(domain: Domain) -> Result<Socket> {
+    let config = config::get();
+    if config.emulate {
+        Ok(Box::new(EmulatedSocket::new(domain)?))
+    } else {
+        Ok(Box::new(HomaSocket::new(domain, config.pages)?))
+    }
+}
+
+/// Returns the port of device `dev`, binding a socket to an ephemeral port on its interface
+/// if no comm holds on to one.
+pub(crate) fn port(dev: i32) -> Result<Arc<Mutex<Port>>> {
+    let mut ports = PORTS.lock()?;
+
+    if let Some(port) = ports.get(&dev).and_then(Weak::upgrade) {
+        return Ok(port);
+    }
+
+    let addr = device::with(dev, |device| device.addr)?;
+
+    let mut socket = socket(Domain::for_address(SocketAddr::new(addr, 0)))?;
+    socket.bind(SocketAddr::new(addr, 0))?;
+    log::info!("device {}: bound to {}", dev, socket.local_addr()?);
+
+    let port = Port::new(socket);
+    ports.insert(dev, Arc::downgrade(&port));
+
+    Ok(port)
+}
+
+pub enum Request<'a> {
     Send(SendRequest<'a>),
-    Recv(RecvRequest<'a, 'b>),
+    Recv(RecvRequest<'a>),
 }
 
 pub struct SendRequest<'a> {
-    comm: &'a mut SendComm,
+    port: Arc<Mutex<Port>>,
+    /// The count of its comm, which may be closed first.
+    inflight: Arc<AtomicUsize>,
+    /// The RPCs of the fragments not yet acknowledged.
+    ids: Vec<u64>,
+    size: usize,
+    /// Keeps `deregMr` of the handle refused until the request is dropped.
+    _mhandle: Option<InUse<'a>>,
+}
+
+pub struct RecvRequest<'a> {
+    port: Arc<Mutex<Port>>,
+    conn: u32,
     id: u64,
+    count: usize,
+    done: bool,
+    buffers: PhantomData<&'a mut [u8]>,
+    /// Keeps `deregMr` of the handles refused until the request is dropped.
+    _mhandles: Vec<InUse<'a>>,
+}
+
+impl<'a> Drop for RecvRequest<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            // After a panic under the lock the matcher cannot be trusted anyway.
+            if let Ok(mut port) = self.port.lock() {
+                if let Some(matcher) = port.matchers.get_mut(&self.conn) {
+                    matcher.cancel(self.id);
+                }
+            }
+        }
+    }
 }
 
-pub struct RecvRequest<'a, 'b> {
-    comm: &'a mut RecvComm,
-    buffer: &'b mut [u8],
+/// The socket of a device, shared by all of its comms.
+///
+/// Requests are the handshakes and fragments sent to its listeners and their connections,
+/// they are dispatched by [`Port::poll`] by the listener or connection in their header.
+/// Responses are dispatched by the kernel, each comm receives those of its own RPCs by id.
+pub(crate) struct Port {
+    pub(crate) socket: Socket,
+    /// Incoming fragments land here before being placed by their header.
+    scratch: Vec<u8>,
+    /// The matcher of every connection, by connection id.
+    matchers: HashMap<u32, Matcher>,
+    /// Connections whose matcher failed, by connection id, until their recv comm learns.
+    failed: HashMap<u32, Error>,
+    /// The connections not yet accepted, by listener id.
+    listeners: HashMap<u32, VecDeque<u32>>,
+    next_conn: u32,
+    next_listener: u32,
+}
+
+impl Port {
+    fn new(socket: Socket) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            socket,
+            scratch: vec![0; HOMA_MAX_MESSAGE_LENGTH],
+            matchers: HashMap::new(),
+            failed: HashMap::new(),
+            listeners: HashMap::new(),
+            next_conn: 0,
+            next_listener: 0,
+        }))
+    }
+
+    /// The matcher of connection `conn`, failing with the error that broke it, if any.
+    fn matcher(&mut self, conn: u32) -> Result<&mut Matcher> {
+        if let Some(err) = self.failed.remove(&conn) {
+            return Err(err);
+        }
+        self.matchers.get_mut(&conn).ok_or(Error::InvalidUsage)
+    }
+
+    /// Receives one RPC, either a handshake that is answered with a new connection or a
+    /// fragment that is handed to the matcher of its connection. Returns false if none was
+    /// pending.
+    ///
+    /// Whichever comm polls, RPCs of other peers and connections are none of its business,
+    /// only a failure of the socket itself is returned.
+    fn poll(&mut self) -> Result<bool> {
+        let (length, addr, id, _) = match self.socket.recv(
+            &mut self.scratch,
+            HomaRecvmsgFlags::REQUEST | HomaRecvmsgFlags::NONBLOCKING,
+            0,
+        ) {
+            Ok(result) => result,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(
+                err @ (roma::Error::Aborted { .. }
+                | roma::Error::TimedOut { .. }
+                | roma::Error::UnknownRpc { .. }
+                | roma::Error::Truncated { .. }),
+            ) => {
+                log::warn!("dropping request: {}", err);
+                return Ok(true);
+            }
+            Err(err) => Err(err)?,
+        };
+
+        let (header, payload) = match Header::decode(&self.scratch[..length]) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                log::warn!("dropping request {} from {}: {}", id, addr, err);
+                self.respond(&[], addr, id);
+                return Ok(true);
+            }
+        };
+
+        match header.kind {
+            Kind::Connect => {
+                if !self.listeners.contains_key(&header.conn) {
+                    log::warn!("connect from {} to unknown listener {}", addr, header.conn);
+                    // An empty response tells the peer that there is no such listener.
+                    self.respond(&[], addr, id);
+                    return Ok(true);
+                }
+
+                self.next_conn += 1;
+                let conn = self.next_conn;
+
+                // A peer that got no answer never uses the connection.
+                if self.respond(&conn.to_le_bytes(), addr, id) {
+                    self.matchers.insert(conn, Matcher::default());
+                    self.listeners
+                        .get_mut(&header.conn)
+                        .unwrap()
+                        .push_back(conn);
+                    log::debug!(
+                        "connection {} from {} to listener {}",
+                        conn,
+                        addr,
+                        header.conn
+                    );
+                }
+            }
+            Kind::Data => {
+                self.respond(&[], addr, id);
+
+                match self.matchers.get_mut(&header.conn) {
+                    Some(matcher) => {
+                        if let Err(err) = matcher.receive(header, payload) {
+                            // Left for the recv comm of the connection to report.
+                            log::warn!("connection {} from {} failed: {}", header.conn, addr, err);
+                            self.matchers.remove(&header.conn);
+                            self.failed.insert(header.conn, err);
+                        }
+                    }
+                    None => log::warn!("dropping fragment of unknown connection {}", header.conn),
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Responds to request `id`, returning false if that failed, in which case its sender
+    /// sees the RPC fail.
+    fn respond(&self, response: &[u8], addr: SocketAddr, id: u64) -> bool {
+        match self.socket.send(response, addr, id, 0) {
+            Ok(_) => true,
+            Err(err) => {
+                log::warn!("failed to respond to {} from {}: {}", id, addr, err);
+                false
+            }
+        }
+    }
 }
 
 pub struct ListenComm {
-    socket: Option<HomaSocket>,
+    port: Arc<Mutex<Port>>,
+    listener: u32,
+}
+
+/// A `connect` waiting for the listener to answer its handshake.
+struct Connecting {
+    port: Arc<Mutex<Port>>,
+    remote: SocketAddr,
+    id: u64,
 }
 
 pub struct SendComm {
-    socket: HomaSocket,
+    port: Arc<Mutex<Port>>,
     remote: SocketAddr,
-    inflight: bool,
+    conn: u32,
+    /// Sends posted and not yet completed, at most `window` of them.
+    inflight: Arc<AtomicUsize>,
+    window: usize,
+    seq: u32,
 }
 
 pub struct RecvComm {
-    socket: HomaSocket,
+    port: Arc<Mutex<Port>>,
+    conn: u32,
 }
 
 pub struct Homa {}
 
-impl Homa {
-    pub fn init(logger: ncclDebugLogger_t) -> Result<()> {
-        crate::logger::Logger::init(LevelFilter::Debug, logger).unwrap();
-        Ok(())
+impl NetPlugin for Homa {
+    type ListenComm = ListenComm;
+    type SendComm = SendComm;
+    type RecvComm = RecvComm;
+    type Request<'a> = Request<'a>;
+    type MemoryHandle = MemoryHandle;
+    type Error = Error;
+
+    /// Registrations are not tied to a comm and shared by range, see [`memory`].
+    const REG_IS_GLOBAL: bool = true;
+
+    fn init(logger: ncclDebugLogger_t) -> Result<()> {
+        // init may run more than once per process, the log crate accepts a single logger.
+        if crate::logger::Logger::init(LevelFilter::Debug, logger).is_err() {
+            log::debug!("logger already installed");
+        }
+        config::init()?;
+        log::set_max_level(config::get().log_level);
+        device::init()
     }
 
-    pub fn devices() -> Result<i32> {
-        Ok(1)
+    fn devices() -> Result<i32> {
+        device::count()?.try_into().map_err(|_| Error::Internal)
     }
 
-    pub fn get_properties(dev: i32) -> Result<ncclNetProperties_v6_t> {
-        if dev == 0 {
-            let name = Box::leak(Box::new(CString::new("default").unwrap()));
-            Ok(ncclNetProperties_v6_t {
-                name: name.as_ptr().cast_mut(),
-                pciPath: null_mut(),
-                guid: 0,
-                ptrSupport: NCCL_PTR_HOST as i32,
-                speed: 1000,
-                port: 0,
-                latency: 0.0,
-                maxComms: i32::MAX,
-                maxRecvs: 1,
-            })
-        } else {
-            Err(Error::Internal)
-        }
+    fn get_properties(dev: i32) -> Result<ncclNetProperties_v6_t> {
+        device::with(dev, |device| ncclNetProperties_v6_t {
+            name: device.name.as_ptr().cast_mut(),
+            pciPath: device
+                .pci_path
+                .as_ref()
+                .map_or(null_mut(), |path| path.as_ptr().cast_mut()),
+            guid: device.guid,
+            ptrSupport: NCCL_PTR_HOST as i32,
+            speed: device.speed,
+            port: 0,
+            latency: 0.0,
+            // No version of the properties has a field for the send depth, NCCL assumes
+            // NCCL_NET_MAX_REQUESTS per comm and retries sends refused by the window.
+            maxComms: i32::MAX,
+            maxRecvs: MAX_RECVS as i32,
+        })
     }
 
-    pub fn listen(dev: i32, handle: &mut [u8]) -> Result<ListenComm> {
-        assert_eq!(dev, 0);
+    fn listen(dev: i32, handle: &mut [u8]) -> Result<ListenComm> {
+        let port = port(dev)?;
 
-        let addr = if_addrs::get_if_addrs()
-            .unwrap()
-            .iter()
-            .map(|i| i.addr.ip())
-            .find(|a| {
-                if let IpAddr::V4(v4) = a {
-                    v4.is_private()
-                } else {
-                    false
-                }
+        let (addr, listener) = {
+            let mut port = port.lock()?;
+            port.next_listener += 1;
+            let listener = port.next_listener;
+            port.listeners.insert(listener, VecDeque::new());
+            (port.socket.local_addr()?, listener)
+        };
+
+        // The stage starts out empty, connect keeps its progress there.
+        handle.fill(0);
+        Handle { addr, listener }.encode(handle);
+
+        Ok(ListenComm { port, listener })
+    }
+
+    /// Sends a handshake to the listener of the handle, returns `None` until it is answered
+    /// and must then be called again with the same handle.
+    fn connect(dev: i32, handle: &mut [u8]) -> Result<Option<SendComm>> {
+        let stage = usize::from_ne_bytes(handle[STAGE].try_into().unwrap()) as *mut Connecting;
+        // Cleared until the handshake is known to be still pending, a failed connect must not
+        // leave a dangling stage behind.
+        handle[STAGE].fill(0);
+
+        let connecting = if stage.is_null() {
+            let target = Handle::decode(handle)?;
+            let port = port(dev)?;
+
+            let header = Header {
+                kind: Kind::Connect,
+                tag: 0,
+                conn: target.listener,
+                seq: 0,
+                offset: 0,
+                length: 0,
+            }
+            .encode();
+            let id = port.lock()?.socket.send(&header, target.addr, 0, 0)?;
+
+            Box::new(Connecting {
+                port,
+                remote: target.addr,
+                id,
             })
-            .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        } else {
+            unsafe { Box::from_raw(stage) }
+        };
 
-        let socket = HomaSocket::new(Domain::IPV4, 20000).unwrap();
+        let mut port = connecting.port.lock()?;
 
-        socket
-            .socket
-            .bind(&(addr, 0).to_socket_addrs().unwrap().next().unwrap().into())
-            .unwrap();
+        // The peer may in turn wait for its handshake to one of our listeners, which must
+        // not depend on accept being called, e.g. by NCCL versions with a blocking connect.
+        while port.poll()? {}
 
-        let local_port = socket
+        let mut conn = [0; 4];
+        let result = port
             .socket
-            .local_addr()
-            .unwrap()
-            .as_socket()
-            .unwrap()
-            .port();
-
-        let h = CString::new(SocketAddr::new(addr, local_port).to_string()).unwrap();
-        let hr = h.as_bytes_with_nul();
-        handle[..hr.len()].copy_from_slice(hr);
-
-        Ok(ListenComm {
-            socket: Some(socket),
-        })
+            .recv(&mut conn, HomaRecvmsgFlags::NONBLOCKING, connecting.id);
+        drop(port);
+
+        match result {
+            Ok((length, ..)) => {
+                if length != conn.len() {
+                    log::warn!("connect refused by {}", connecting.remote);
+                    return Err(Error::InvalidArgument);
+                }
+
+                Ok(Some(SendComm {
+                    port: connecting.port,
+                    remote: connecting.remote,
+                    conn: u32::from_le_bytes(conn),
+                    inflight: Arc::default(),
+                    window: config::get().window,
+                    seq: 0,
+                }))
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                let stage = Box::into_raw(connecting) as usize;
+                handle[STAGE].copy_from_slice(&stage.to_ne_bytes());
+                Ok(None)
+            }
+            Err(err) => Err(err)?,
+        }
     }
 
-    pub fn connect(dev: c_int, handle: &[u8]) -> Result<SendComm> {
-        assert_eq!(dev, 0);
+    /// Returns the next connection whose handshake arrived, or `None` if there is none yet.
+    fn accept(listen_comm: &mut ListenComm) -> Result<Option<RecvComm>> {
+        let mut port = listen_comm.port.lock()?;
 
-        let handle = CStr::from_bytes_until_nul(handle)
-            .unwrap()
-            .to_str()
-            .unwrap();
+        loop {
+            let pending = port
+                .listeners
+                .get_mut(&listen_comm.listener)
+                .ok_or(Error::InvalidUsage)?;
 
-        let socket = HomaSocket::new(Domain::IPV4, 20000).unwrap();
+            if let Some(conn) = pending.pop_front() {
+                return Ok(Some(RecvComm {
+                    port: listen_comm.port.clone(),
+                    conn,
+                }));
+            }
 
-        Ok(SendComm {
-            socket,
-            remote: handle.parse().unwrap(),
-            inflight: false,
-        })
+            if !port.poll()? {
+                return Ok(None);
+            }
+        }
     }
 
-    pub fn accept(listen_comm: &mut ListenComm) -> Result<RecvComm> {
-        Ok(RecvComm {
-            socket: listen_comm.socket.take().unwrap(),
-        })
+    /// Registrations are not tied to the comm, see [`memory`].
+    fn reg_mr(
+        _comm: &mut Comm<Self>,
+        data: *mut c_void,
+        size: usize,
+        type_: i32,
+    ) -> Result<MemoryHandle> {
+        memory::register(data as usize, size, type_)
     }
 
-    pub fn isend<'a, 'b>(
-        send_comm: &'a mut SendComm,
-        buf: &[u8],
-    ) -> Result<Option<Request<'a, 'b>>> {
-        if send_comm.inflight {
+    fn dereg_mr(_comm: &mut Comm<Self>, mhandle: &MemoryHandle) -> Result<()> {
+        memory::deregister(mhandle)
+    }
+
+    fn isend<'a>(
+        send_comm: &mut SendComm,
+        buf: &'a [u8],
+        tag: i32,
+        mhandle: Option<&'a MemoryHandle>,
+    ) -> Result<Option<Request<'a>>> {
+        if send_comm.inflight.load(Ordering::Relaxed) == send_comm.window {
             return Ok(None);
         }
 
-        send_comm.inflight = true;
+        let mhandle = mhandle.map(|mhandle| mhandle.acquire(buf)).transpose()?;
 
-        let id = send_comm
-            .socket
-            .send(buf, send_comm.remote, 0, buf.len().try_into().unwrap())?;
+        // Messages above the RPC size limit go out as several RPCs in parallel, the
+        // configuration keeps a fragment and its header within it.
+        let fragment = config::get().max_message_size;
+        let length: u32 = buf.len().try_into().map_err(|_| Error::InvalidArgument)?;
+
+        let port = send_comm.port.lock()?;
+
+        let mut ids = vec![];
+        for offset in (0..buf.len().max(1)).step_by(fragment) {
+            let payload = &buf[offset..buf.len().min(offset + fragment)];
+
+            let header = Header {
+                kind: Kind::Data,
+                tag,
+                conn: send_comm.conn,
+                seq: send_comm.seq,
+                offset: offset as u32,
+                length,
+            }
+            .encode();
+
+            let id = port.socket.send_vectored(
+                &[IoSlice::new(&header), IoSlice::new(payload)],
+                send_comm.remote,
+                0,
+                0,
+            );
+
+            match id {
+                Ok(id) => ids.push(id),
+                Err(err) => {
+                    // The receiver can never complete the message now.
+                    for id in ids {
+                        let _ = port.socket.abort(id, 0);
+                    }
+                    return Err(err)?;
+                }
+            }
+        }
+
+        drop(port);
+
+        send_comm.inflight.fetch_add(1, Ordering::Relaxed);
+        send_comm.seq = send_comm.seq.wrapping_add(1);
 
         Ok(Some(Request::Send(SendRequest {
-            comm: send_comm,
-            id,
+            port: send_comm.port.clone(),
+            inflight: send_comm.inflight.clone(),
+            ids,
+            size: buf.len(),
+            _mhandle: mhandle,
         })))
     }
 
-    pub fn irecv<'a, 'b>(
-        recv_comm: &'a mut RecvComm,
-        buf: &'b mut [u8],
-    ) -> Result<Request<'a, 'b>> {
-        Ok(Request::Recv(RecvRequest {
-            buffer: buf,
-            comm: recv_comm,
-        }))
+    /// Posts a grouped receive of one message per buffer, matched by tag.
+    fn irecv<'a>(
+        recv_comm: &mut RecvComm,
+        buffers: Vec<Buffer<'a, MemoryHandle>>,
+    ) -> Result<Option<Request<'a>>> {
+        let mhandles = buffers
+            .iter()
+            .filter_map(|buffer| buffer.mhandle.map(|mhandle| mhandle.acquire(buffer.data)))
+            .collect::<Result<_>>()?;
+
+        let mut buffers: Vec<_> = buffers
+            .into_iter()
+            .map(|buffer| (buffer.data, buffer.tag))
+            .collect();
+
+        if buffers.is_empty() || buffers.len() > MAX_RECVS {
+            return Err(Error::InvalidArgument);
+        }
+
+        // The request borrows the buffers for as long as the matcher holds on to them.
+        let id = {
+            let mut port = recv_comm.port.lock()?;
+            let matcher = port.matcher(recv_comm.conn)?;
+            unsafe { matcher.post(&mut buffers)? }
+        };
+
+        Ok(Some(Request::Recv(RecvRequest {
+            port: recv_comm.port.clone(),
+            conn: recv_comm.conn,
+            id,
+            count: buffers.len(),
+            done: false,
+            buffers: PhantomData,
+            _mhandles: mhandles,
+        })))
     }
 
-    pub fn test(request: &mut Request) -> Result<Option<i32>> {
+    fn test(request: &mut Request) -> Result<Option<Vec<i32>>> {
         match request {
             Request::Send(req) => {
-                match req
-                    .comm
-                    .socket
-                    .recv(&mut [], HomaRecvmsgFlags::NONBLOCKING, req.id)
-                {
-                    Ok((_, _, _, cookie)) => {
-                        req.comm.inflight = false;
-                        Ok(Some(cookie.try_into().unwrap()))
+                let mut port = req.port.lock()?;
+
+                while let Some(&id) = req.ids.last() {
+                    match port.socket.recv(&mut [], HomaRecvmsgFlags::NONBLOCKING, id) {
+                        Ok(_) => {
+                            req.ids.pop();
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                        Err(err) => {
+                            req.ids.clear();
+                            req.inflight.fetch_sub(1, Ordering::Relaxed);
+                            Err(err)?
+                        }
                     }
-                    Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
-                    Err(err) => Err(err)?,
                 }
+
+                req.inflight.fetch_sub(1, Ordering::Relaxed);
+                Ok(Some(vec![req
+                    .size
+                    .try_into()
+                    .map_err(|_| Error::Internal)?]))
             }
-            Request::Recv(req) => match req.comm.socket.recv(
-                req.buffer,
-                HomaRecvmsgFlags::REQUEST | HomaRecvmsgFlags::NONBLOCKING,
-                0,
-            ) {
-                Ok((length, addr, id, _)) => {
-                    req.comm.socket.send(&[], addr, id, 0).unwrap();
-                    Ok(Some(length.try_into().unwrap()))
+            Request::Recv(req) => {
+                let mut port = req.port.lock()?;
+
+                loop {
+                    let matcher = port.matcher(req.conn)?;
+
+                    if let Some(sizes) = matcher.take(req.id, req.count) {
+                        req.done = true;
+                        return Ok(Some(
+                            sizes
+                                .into_iter()
+                                .map(|size| size.try_into().map_err(|_| Error::Internal))
+                                .collect::<Result<_>>()?,
+                        ));
+                    }
+
+                    if !port.poll()? {
+                        return Ok(None);
+                    }
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
-                Err(err) => Err(err)?,
-            },
+            }
         }
     }
 
-    pub fn close_send(_send_comm: SendComm) -> Result<()> {
+    fn close_send(_send_comm: SendComm) -> Result<()> {
         Ok(())
     }
 
-    pub fn close_recv(_recv_comm: RecvComm) -> Result<()> {
+    fn close_recv(recv_comm: RecvComm) -> Result<()> {
+        let mut port = recv_comm.port.lock()?;
+        port.matchers.remove(&recv_comm.conn);
+        port.failed.remove(&recv_comm.conn);
         Ok(())
     }
 
-    pub fn close_listen(_listen_comm: ListenComm) -> Result<()> {
+    fn close_listen(listen_comm: ListenComm) -> Result<()> {
+        let mut port = listen_comm.port.lock()?;
+
+        // Connections that were never accepted have no one to receive on them.
+        if let Some(pending) = port.listeners.remove(&listen_comm.listener) {
+            for conn in pending {
+                port.matchers.remove(&conn);
+                port.failed.remove(&conn);
+            }
+        }
+
         Ok(())
     }
 }
@@ -3,41 +3,36 @@
 #![feature(strict_provenance)]
 #![feature(c_variadic)]
 
-use nccl_net_sys::ncclNet_v6_t;
-
-mod binding;
+pub mod aggregator;
+pub mod collnet;
+pub mod config;
+pub mod device;
 pub mod error;
 pub mod homa;
 pub mod logger;
+pub mod matcher;
+pub mod memory;
+pub mod reduce;
+pub mod tuner;
+pub mod wire;
 
-#[export_name = "ncclNetPlugin_v6"]
-pub static mut PLUGIN: ncclNet_v6_t = ncclNet_v6_t {
-    name: b"homa\0".as_ptr().cast(),
-    init: Some(binding::init),
-    devices: Some(binding::devices),
-    getProperties: Some(binding::get_properties),
-    listen: Some(binding::listen),
-    connect: Some(binding::connect),
-    accept: Some(binding::accept),
-    regMr: Some(binding::reg_mr),
-    regMrDmaBuf: None,
-    deregMr: Some(binding::dereg_mr),
-    isend: Some(binding::isend),
-    irecv: Some(binding::irecv),
-    iflush: None,
-    test: Some(binding::test),
-    closeSend: Some(binding::close_send),
-    closeRecv: Some(binding::close_recv),
-    closeListen: Some(binding::close_listen),
-};
+nccl_net_sys::export_net_plugin!(homa::Homa, "homa");
+nccl_net_sys::export_coll_net_plugin!(collnet::HomaCollNet, "homa");
+nccl_net_sys::export_tuner_plugin!(tuner::HomaTuner, "homa");
 
 #[cfg(test)]
 mod test {
-    use crate::binding::*;
-    use nccl_net_sys::{ncclDebugLogLevel, ncclResult_t, NCCL_NET_HANDLE_MAXSIZE};
+    use crate::homa::Homa;
+    use nccl_net_sys::plugin::*;
+    use nccl_net_sys::{
+        ncclDataType_t, ncclDebugLogLevel, ncclFunc_t, ncclNetDeviceType, ncclNetProperties_v4_t,
+        ncclNetProperties_v6_t, ncclNetProperties_v8_t, ncclRedOp_t, ncclResult_t,
+        NCCL_NET_HANDLE_MAXSIZE, NCCL_NET_MAX_REQUESTS, NCCL_PTR_HOST,
+    };
+    use socket2::Domain;
     use std::{
         ffi::{c_char, c_int, c_ulong, c_void, CStr},
-        ptr::null_mut,
+        ptr::{null_mut, NonNull},
     };
 
     unsafe extern "C" fn logger(
@@ -61,25 +56,11 @@ mod test {
     #[test]
     fn roundtrip() {
         unsafe {
-            let ret = init(Some(logger));
-            assert_eq!(ret, ncclResult_t::ncclSuccess);
-
-            let mut handle = [0u8; NCCL_NET_HANDLE_MAXSIZE as usize];
-            let mut listen_comm: *mut c_void = null_mut();
-            let ret = listen(0, handle.as_mut_ptr().cast(), &mut listen_comm);
-            assert_eq!(ret, ncclResult_t::ncclSuccess);
-
-            let mut send_comm: *mut c_void = null_mut();
-            let ret = connect(0, handle.as_mut_ptr().cast(), &mut send_comm);
-            assert_eq!(ret, ncclResult_t::ncclSuccess);
-
-            let mut recv_comm: *mut c_void = null_mut();
-            let ret = accept(listen_comm, &mut recv_comm);
-            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            let (send_comm, recv_comm) = connect_pair();
 
             let mut data = b"hello penny\0".to_vec();
             let mut send_req: *mut c_void = null_mut();
-            let ret = isend(
+            let ret = isend::<Homa>(
                 send_comm,
                 data.as_mut_ptr().cast(),
                 data.len().try_into().unwrap(),
@@ -93,12 +74,13 @@ mod test {
             let mut buf = vec![0u8; 100];
             let mut bufs = [buf.as_mut_ptr().cast()];
             let mut sizes = [data.len().try_into().unwrap()];
-            let ret = irecv(
+            let mut tags = [0];
+            let ret = irecv::<Homa>(
                 recv_comm,
                 1,
                 bufs.as_mut_ptr(),
                 sizes.as_mut_ptr(),
-                null_mut(),
+                tags.as_mut_ptr(),
                 null_mut(),
                 &mut recv_req,
             );
@@ -107,7 +89,7 @@ mod test {
             loop {
                 let mut done = 0;
                 let mut size = 0;
-                let ret = test(recv_req, &mut done, &mut size);
+                let ret = test::<Homa>(recv_req, &mut done, &mut size);
                 assert_eq!(ret, ncclResult_t::ncclSuccess);
                 if done == 1 {
                     assert_eq!(size, data.len().try_into().unwrap());
@@ -118,7 +100,7 @@ mod test {
             loop {
                 let mut done = 0;
                 let mut size = 0;
-                let ret = test(send_req, &mut done, &mut size);
+                let ret = test::<Homa>(send_req, &mut done, &mut size);
                 assert_eq!(ret, ncclResult_t::ncclSuccess);
                 if done == 1 {
                     assert_eq!(size, data.len().try_into().unwrap());
@@ -129,4 +111,650 @@ mod test {
             assert_eq!(buf[..data.len()], data);
         }
     }
+
+    #[test]
+    fn properties() {
+        unsafe {
+            let ret = init::<Homa>(Some(logger));
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let mut ndev = 0;
+            let ret = devices::<Homa>(&mut ndev);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            assert!(ndev >= 1);
+
+            for dev in 0..ndev {
+                let mut props: ncclNetProperties_v6_t = std::mem::zeroed();
+                let ret = get_properties::<Homa>(dev, &mut props);
+                assert_eq!(ret, ncclResult_t::ncclSuccess);
+                assert!(!CStr::from_ptr(props.name).to_bytes().is_empty());
+                assert!(props.speed > 0);
+            }
+
+            let mut props: ncclNetProperties_v6_t = std::mem::zeroed();
+            let ret = get_properties::<Homa>(ndev, &mut props);
+            assert_eq!(ret, ncclResult_t::ncclInvalidArgument);
+        }
+    }
+
+    /// Listens on device 0, returning the listen comm and its handle.
+    unsafe fn listen_comm() -> (*mut c_void, [u8; NCCL_NET_HANDLE_MAXSIZE as usize]) {
+        let ret = init::<Homa>(Some(logger));
+        assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+        let mut handle = [0u8; NCCL_NET_HANDLE_MAXSIZE as usize];
+        let mut listen_comm: *mut c_void = null_mut();
+        let ret = listen::<Homa>(0, handle.as_mut_ptr().cast(), &mut listen_comm);
+        assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+        (listen_comm, handle)
+    }
+
+    /// Calls connect and accept until both return a comm, as NCCL does.
+    unsafe fn establish(listen_comm: *mut c_void, handle: &mut [u8]) -> (*mut c_void, *mut c_void) {
+        let mut send_comm: *mut c_void = null_mut();
+        let mut recv_comm: *mut c_void = null_mut();
+
+        while send_comm.is_null() || recv_comm.is_null() {
+            if send_comm.is_null() {
+                let ret = connect::<Homa>(0, handle.as_mut_ptr().cast(), &mut send_comm);
+                assert_eq!(ret, ncclResult_t::ncclSuccess);
+            }
+            if recv_comm.is_null() {
+                let ret = accept::<Homa>(listen_comm, &mut recv_comm);
+                assert_eq!(ret, ncclResult_t::ncclSuccess);
+            }
+        }
+
+        (send_comm, recv_comm)
+    }
+
+    /// Connects a send comm to a recv comm on device 0.
+    unsafe fn connect_pair() -> (*mut c_void, *mut c_void) {
+        let (listen_comm, mut handle) = listen_comm();
+        establish(listen_comm, &mut handle)
+    }
+
+    unsafe fn send(send_comm: *mut c_void, data: &mut [u8], tag: c_int) -> *mut c_void {
+        let mut send_req: *mut c_void = null_mut();
+        let ret = isend::<Homa>(
+            send_comm,
+            data.as_mut_ptr().cast(),
+            data.len().try_into().unwrap(),
+            tag,
+            null_mut(),
+            &mut send_req,
+        );
+        assert_eq!(ret, ncclResult_t::ncclSuccess);
+        send_req
+    }
+
+    unsafe fn recv(
+        recv_comm: *mut c_void,
+        bufs: &mut [Vec<u8>],
+        tags: &mut [c_int],
+    ) -> *mut c_void {
+        let mut data: Vec<*mut c_void> = bufs.iter_mut().map(|b| b.as_mut_ptr().cast()).collect();
+        let mut sizes: Vec<c_int> = bufs.iter().map(|b| b.len().try_into().unwrap()).collect();
+        let mut recv_req: *mut c_void = null_mut();
+        let ret = irecv::<Homa>(
+            recv_comm,
+            bufs.len().try_into().unwrap(),
+            data.as_mut_ptr(),
+            sizes.as_mut_ptr(),
+            tags.as_mut_ptr(),
+            null_mut(),
+            &mut recv_req,
+        );
+        assert_eq!(ret, ncclResult_t::ncclSuccess);
+        recv_req
+    }
+
+    /// Polls the request until it completes, filling in `sizes`.
+    unsafe fn wait(request: *mut c_void, sizes: &mut [c_int]) {
+        loop {
+            let mut done = 0;
+            let ret = test::<Homa>(request, &mut done, sizes.as_mut_ptr());
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            if done == 1 {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn window() {
+        unsafe {
+            let (send_comm, recv_comm) = connect_pair();
+
+            let mut data = b"hello penny\0".to_vec();
+            let mut send_reqs = vec![];
+            for i in 0..=NCCL_NET_MAX_REQUESTS {
+                let send_req = send(send_comm, &mut data, 0);
+                // The send past the window has to be retried later.
+                assert_eq!(send_req.is_null(), i == NCCL_NET_MAX_REQUESTS);
+                if !send_req.is_null() {
+                    send_reqs.push(send_req);
+                }
+            }
+
+            let mut bufs = vec![vec![0u8; 100]];
+            for _ in 0..NCCL_NET_MAX_REQUESTS {
+                wait(recv(recv_comm, &mut bufs, &mut [0]), &mut [0]);
+                assert_eq!(bufs[0][..data.len()], data);
+            }
+
+            for send_req in send_reqs {
+                wait(send_req, &mut [0]);
+            }
+        }
+    }
+
+    #[test]
+    fn grouped() {
+        unsafe {
+            let (send_comm, recv_comm) = connect_pair();
+
+            // Sent out of order, each lands in the buffer posted for its tag.
+            let send_reqs = [
+                send(send_comm, &mut b"three".to_vec(), 3),
+                send(send_comm, &mut b"one".to_vec(), 1),
+                send(send_comm, &mut [], 2),
+            ];
+
+            let mut bufs = vec![vec![0u8; 16]; 3];
+            let mut sizes = [0; 3];
+            wait(recv(recv_comm, &mut bufs, &mut [1, 2, 3]), &mut sizes);
+
+            assert_eq!(sizes, [3, 0, 5]);
+            assert_eq!(bufs[0][..3], *b"one");
+            assert_eq!(bufs[2][..5], *b"three");
+
+            for send_req in send_reqs {
+                wait(send_req, &mut [0]);
+            }
+        }
+    }
+
+    #[test]
+    fn unexpected() {
+        unsafe {
+            let (send_comm, recv_comm) = connect_pair();
+
+            let send_reqs = [
+                send(send_comm, &mut b"early".to_vec(), 7),
+                send(send_comm, &mut b"first".to_vec(), 5),
+                send(send_comm, &mut b"late".to_vec(), 7),
+            ];
+
+            // Both messages tagged 7 are held back until receives are posted for them,
+            // and are then delivered in order.
+            let mut bufs = vec![vec![0u8; 16]];
+            let mut sizes = [0];
+            for (tag, expected) in [(5, &b"first"[..]), (7, b"early"), (7, b"late")] {
+                wait(recv(recv_comm, &mut bufs, &mut [tag]), &mut sizes);
+                assert_eq!(bufs[0][..sizes[0] as usize], *expected);
+            }
+
+            for send_req in send_reqs {
+                wait(send_req, &mut [0]);
+            }
+        }
+    }
+
+    #[test]
+    fn fragmented() {
+        unsafe {
+            let (send_comm, recv_comm) = connect_pair();
+
+            let mut data: Vec<u8> = (0..3_500_000).map(|i| i as u8).collect();
+            let send_req = send(send_comm, &mut data, 0);
+
+            let mut bufs = vec![vec![0u8; 4 << 20]];
+            let mut sizes = [0];
+            wait(recv(recv_comm, &mut bufs, &mut [0]), &mut sizes);
+            wait(send_req, &mut [0]);
+
+            assert_eq!(sizes[0] as usize, data.len());
+            assert_eq!(bufs[0][..data.len()], data);
+        }
+    }
+
+    #[test]
+    fn demux() {
+        unsafe {
+            let (listen_comm, mut handle) = listen_comm();
+
+            // Every peer connecting through the same handle gets a recv comm of its own.
+            let mut handles = [handle; 3];
+            let mut send_comms = [null_mut(); 3];
+            for (i, handle) in handles.iter_mut().enumerate() {
+                let ret = connect::<Homa>(0, handle.as_mut_ptr().cast(), &mut send_comms[i]);
+                assert_eq!(ret, ncclResult_t::ncclSuccess);
+            }
+
+            let mut recv_comms = vec![];
+            while recv_comms.len() < 3 {
+                let mut recv_comm: *mut c_void = null_mut();
+                let ret = accept::<Homa>(listen_comm, &mut recv_comm);
+                assert_eq!(ret, ncclResult_t::ncclSuccess);
+                if !recv_comm.is_null() {
+                    recv_comms.push(recv_comm);
+                }
+            }
+
+            for (i, handle) in handles.iter_mut().enumerate() {
+                while send_comms[i].is_null() {
+                    let ret = connect::<Homa>(0, handle.as_mut_ptr().cast(), &mut send_comms[i]);
+                    assert_eq!(ret, ncclResult_t::ncclSuccess);
+                }
+            }
+
+            // Connections are accepted in the order their handshakes arrived, so match the
+            // comms up by what arrives on them.
+            let send_reqs: Vec<_> = send_comms
+                .iter()
+                .enumerate()
+                .map(|(i, send_comm)| send(*send_comm, &mut vec![i as u8; i + 1], 0))
+                .collect();
+
+            let mut seen = vec![];
+            for recv_comm in recv_comms {
+                let mut bufs = vec![vec![0u8; 16]];
+                let mut sizes = [0];
+                wait(recv(recv_comm, &mut bufs, &mut [0]), &mut sizes);
+                let i = bufs[0][0] as usize;
+                assert_eq!(bufs[0][..sizes[0] as usize], vec![i as u8; i + 1]);
+                seen.push(i);
+            }
+            seen.sort();
+            assert_eq!(seen, [0, 1, 2]);
+
+            for send_req in send_reqs {
+                wait(send_req, &mut [0]);
+            }
+
+            // A later peer is still accepted.
+            establish(listen_comm, &mut handle);
+        }
+    }
+
+    #[test]
+    fn shared() {
+        unsafe {
+            let (first, mut handle) = listen_comm();
+            let (second, other) = listen_comm();
+
+            // Both listeners, and every comm connected through them, use the device's socket.
+            let a = crate::wire::Handle::decode(&handle).unwrap();
+            let b = crate::wire::Handle::decode(&other).unwrap();
+            assert_eq!(a.addr, b.addr);
+            assert_ne!(a.listener, b.listener);
+
+            let (send_comm, recv_comm) = establish(first, &mut handle);
+            let send_req = send(send_comm, &mut b"shared".to_vec(), 0);
+
+            let mut bufs = vec![vec![0u8; 16]];
+            let mut sizes = [0];
+            wait(recv(recv_comm, &mut bufs, &mut [0]), &mut sizes);
+            assert_eq!(bufs[0][..sizes[0] as usize], *b"shared");
+            wait(send_req, &mut [0]);
+
+            let ret = close_listen::<Homa>(second);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+        }
+    }
+
+    #[test]
+    fn isolated() {
+        unsafe {
+            let (listen_comm, mut handle) = listen_comm();
+            let (first_send, first_recv) = establish(listen_comm, &mut handle);
+            let (second_send, second_recv) = establish(listen_comm, &mut handle);
+
+            // A stray request and a message too large for its buffer, whichever comm polls
+            // first receives them.
+            let target = crate::wire::Handle::decode(&handle).unwrap();
+            let stray = crate::homa::socket(Domain::for_address(target.addr)).unwrap();
+            stray.send(b"??", target.addr, 0, 0).unwrap();
+
+            let mut small = vec![vec![0u8; 2]];
+            let first_req = recv(first_recv, &mut small, &mut [0]);
+            let large_req = send(first_send, &mut b"too large".to_vec(), 0);
+
+            let second_req = send(second_send, &mut b"fine".to_vec(), 0);
+            let mut bufs = vec![vec![0u8; 16]];
+            let mut sizes = [0];
+            wait(recv(second_recv, &mut bufs, &mut [0]), &mut sizes);
+            assert_eq!(bufs[0][..sizes[0] as usize], *b"fine");
+            wait(second_req, &mut [0]);
+
+            // Only the comm of the broken connection fails.
+            let mut done = 0;
+            let ret = test::<Homa>(first_req, &mut done, sizes.as_mut_ptr());
+            assert_eq!(ret, ncclResult_t::ncclInvalidUsage);
+            wait(large_req, &mut [0]);
+        }
+    }
+
+    #[test]
+    fn closed() {
+        unsafe {
+            let (send_comm, recv_comm) = connect_pair();
+
+            // Requests do not depend on their comm staying open.
+            let send_req = send(send_comm, &mut b"bye".to_vec(), 0);
+            let ret = close_send::<Homa>(send_comm);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let mut bufs = vec![vec![0u8; 16]];
+            let mut sizes = [0];
+            wait(recv(recv_comm, &mut bufs, &mut [0]), &mut sizes);
+            assert_eq!(bufs[0][..sizes[0] as usize], *b"bye");
+            wait(send_req, &mut [0]);
+
+            // A receive that can no longer complete fails.
+            let recv_req = recv(recv_comm, &mut bufs, &mut [0]);
+            let ret = close_recv::<Homa>(recv_comm);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            let mut done = 0;
+            let ret = test::<Homa>(recv_req, &mut done, sizes.as_mut_ptr());
+            assert_eq!(ret, ncclResult_t::ncclInvalidUsage);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        unsafe {
+            let (send_comm, recv_comm) = connect_pair();
+
+            let mut data = [0u8; 4];
+            let mut request: *mut c_void = null_mut();
+            let ret = isend::<Homa>(
+                send_comm,
+                data.as_mut_ptr().cast(),
+                -1,
+                0,
+                null_mut(),
+                &mut request,
+            );
+            assert_eq!(ret, ncclResult_t::ncclInvalidArgument);
+
+            let mut bufs = [data.as_mut_ptr().cast()];
+            let mut sizes = [-4];
+            let mut tags = [0];
+            let ret = irecv::<Homa>(
+                recv_comm,
+                1,
+                bufs.as_mut_ptr(),
+                sizes.as_mut_ptr(),
+                tags.as_mut_ptr(),
+                null_mut(),
+                &mut request,
+            );
+            assert_eq!(ret, ncclResult_t::ncclInvalidArgument);
+
+            // A panic is reported to NCCL instead of unwinding into it.
+            let ret = guard(|| panic!("boom"));
+            assert_eq!(ret, ncclResult_t::ncclInternalError);
+        }
+    }
+
+    #[test]
+    fn registered() {
+        unsafe {
+            let (send_comm, recv_comm) = connect_pair();
+
+            let mut data = [7u8; 64];
+            let mut send_mhandle: *mut c_void = null_mut();
+            let ret = reg_mr::<Homa>(
+                send_comm,
+                data.as_mut_ptr().cast(),
+                32,
+                NCCL_PTR_HOST as c_int,
+                &mut send_mhandle,
+            );
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            // Only within the registered range.
+            let mut request: *mut c_void = null_mut();
+            let ret = isend::<Homa>(
+                send_comm,
+                data[16..].as_mut_ptr().cast(),
+                32,
+                0,
+                send_mhandle,
+                &mut request,
+            );
+            assert_eq!(ret, ncclResult_t::ncclInvalidArgument);
+
+            let mut send_req: *mut c_void = null_mut();
+            let ret = isend::<Homa>(
+                send_comm,
+                data.as_mut_ptr().cast(),
+                16,
+                0,
+                send_mhandle,
+                &mut send_req,
+            );
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let mut buf = vec![0u8; 16];
+            let mut recv_mhandle: *mut c_void = null_mut();
+            let ret = reg_mr::<Homa>(
+                recv_comm,
+                buf.as_mut_ptr().cast(),
+                16,
+                NCCL_PTR_HOST as c_int,
+                &mut recv_mhandle,
+            );
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let mut bufs = [buf.as_mut_ptr().cast()];
+            let mut sizes = [16];
+            let mut tags = [0];
+            let mut mhandles = [recv_mhandle];
+            let mut recv_req: *mut c_void = null_mut();
+            let ret = irecv::<Homa>(
+                recv_comm,
+                1,
+                bufs.as_mut_ptr(),
+                sizes.as_mut_ptr(),
+                tags.as_mut_ptr(),
+                mhandles.as_mut_ptr(),
+                &mut recv_req,
+            );
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            // Not while requests are using the handles.
+            let ret = dereg_mr::<Homa>(send_comm, send_mhandle);
+            assert_eq!(ret, ncclResult_t::ncclInvalidUsage);
+            let ret = dereg_mr::<Homa>(recv_comm, recv_mhandle);
+            assert_eq!(ret, ncclResult_t::ncclInvalidUsage);
+
+            wait(recv_req, &mut [0]);
+            wait(send_req, &mut [0]);
+            assert_eq!(buf, [7; 16]);
+
+            let ret = dereg_mr::<Homa>(send_comm, send_mhandle);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            let ret = dereg_mr::<Homa>(recv_comm, recv_mhandle);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+        }
+    }
+
+    #[test]
+    fn versions() {
+        unsafe {
+            let plugin = crate::NCCL_NET_PLUGIN_V4;
+            let ret = plugin.init.unwrap()(Some(logger));
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let mut props: ncclNetProperties_v4_t = std::mem::zeroed();
+            let ret = plugin.getProperties.unwrap()(0, &mut props);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            assert!(props.speed > 0);
+
+            // v4 connect and accept block, connect answers its own handshake meanwhile.
+            let mut handle = [0u8; NCCL_NET_HANDLE_MAXSIZE as usize];
+            let mut listen_comm: *mut c_void = null_mut();
+            let ret = plugin.listen.unwrap()(0, handle.as_mut_ptr().cast(), &mut listen_comm);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let mut send_comm: *mut c_void = null_mut();
+            let ret = plugin.connect.unwrap()(0, handle.as_mut_ptr().cast(), &mut send_comm);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            assert!(!send_comm.is_null());
+
+            let mut recv_comm: *mut c_void = null_mut();
+            let ret = plugin.accept.unwrap()(listen_comm, &mut recv_comm);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            assert!(!recv_comm.is_null());
+
+            let mut data = b"hello v4".to_vec();
+            let mut send_req: *mut c_void = null_mut();
+            let ret = plugin.isend.unwrap()(
+                send_comm,
+                data.as_mut_ptr().cast(),
+                data.len().try_into().unwrap(),
+                null_mut(),
+                &mut send_req,
+            );
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let mut buf = vec![0u8; 16];
+            let mut recv_req: *mut c_void = null_mut();
+            let ret = plugin.irecv.unwrap()(
+                recv_comm,
+                buf.as_mut_ptr().cast(),
+                buf.len().try_into().unwrap(),
+                null_mut(),
+                &mut recv_req,
+            );
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let mut size = [0];
+            wait(recv_req, &mut size);
+            wait(send_req, &mut [0]);
+            assert_eq!(buf[..size[0] as usize], data);
+
+            let plugin = crate::NCCL_NET_PLUGIN_V8;
+            let mut props: ncclNetProperties_v8_t = std::mem::zeroed();
+            let ret = plugin.getProperties.unwrap()(0, &mut props);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            assert_eq!(props.maxRecvs, 8);
+            assert_eq!(props.netDeviceType, ncclNetDeviceType::NCCL_NET_DEVICE_HOST);
+            assert_eq!(props.regIsGlobal, 1);
+
+            // No device handle is handed out, not even while connecting.
+            let ret = plugin.listen.unwrap()(0, handle.as_mut_ptr().cast(), &mut listen_comm);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            send_comm = null_mut();
+            recv_comm = null_mut();
+            while send_comm.is_null() || recv_comm.is_null() {
+                if send_comm.is_null() {
+                    let mut send_dev_comm = NonNull::dangling().as_ptr();
+                    let ret = plugin.connect.unwrap()(
+                        0,
+                        handle.as_mut_ptr().cast(),
+                        &mut send_comm,
+                        &mut send_dev_comm,
+                    );
+                    assert_eq!(ret, ncclResult_t::ncclSuccess);
+                    assert!(send_dev_comm.is_null());
+                }
+                if recv_comm.is_null() {
+                    let mut recv_dev_comm = NonNull::dangling().as_ptr();
+                    let ret =
+                        plugin.accept.unwrap()(listen_comm, &mut recv_comm, &mut recv_dev_comm);
+                    assert_eq!(ret, ncclResult_t::ncclSuccess);
+                    assert!(recv_dev_comm.is_null());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn collnet() {
+        unsafe {
+            let plugin = crate::NCCL_COLL_NET_PLUGIN_V6;
+            let ret = plugin.init.unwrap()(Some(logger));
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            for (data_type, op, expected) in [
+                (ncclDataType_t::ncclBfloat16, ncclRedOp_t::ncclSum, 1),
+                (ncclDataType_t::ncclUint64, ncclRedOp_t::ncclMin, 1),
+                (ncclDataType_t::ncclFloat32, ncclRedOp_t::ncclAvg, 0),
+                (ncclDataType_t(42), ncclRedOp_t::ncclSum, 0),
+            ] {
+                let mut supported = -1;
+                let ret = plugin.reduceSupport.unwrap()(data_type, op, &mut supported);
+                assert_eq!(ret, ncclResult_t::ncclSuccess);
+                assert_eq!(supported, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn tuner() {
+        unsafe {
+            let plugin = crate::NCCL_TUNER_PLUGIN_V2;
+            let mut context = null_mut();
+            let ret = plugin.init.unwrap()(16, 2, Some(logger), &mut context);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let (mut algorithm, mut protocol, mut channels) = (-1, -1, -1);
+            let ret = plugin.getCollInfo.unwrap()(
+                context,
+                ncclFunc_t::ncclFuncAllReduce,
+                1024,
+                0,
+                0,
+                1,
+                &mut algorithm,
+                &mut protocol,
+                &mut channels,
+            );
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            // The built-in table leaves the choice to NCCL.
+            assert_eq!((algorithm, protocol, channels), (-1, -1, -1));
+
+            let ret = plugin.destroy.unwrap()(context);
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            // v1 keeps the context itself, and has none once destroyed.
+            let plugin = crate::NCCL_TUNER_PLUGIN_V1;
+            let ret = plugin.init.unwrap()(16, 2, Some(logger));
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let (mut algorithm, mut protocol) = (-1, -1);
+            let ret = plugin.getCollInfo.unwrap()(
+                ncclFunc_t::ncclFuncAllGather,
+                1 << 20,
+                0,
+                0,
+                1,
+                &mut algorithm,
+                &mut protocol,
+                &mut channels,
+            );
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+            assert_eq!((algorithm, protocol), (-1, -1));
+
+            let ret = plugin.destroy.unwrap()();
+            assert_eq!(ret, ncclResult_t::ncclSuccess);
+
+            let ret = plugin.getCollInfo.unwrap()(
+                ncclFunc_t::ncclFuncAllGather,
+                1 << 20,
+                0,
+                0,
+                1,
+                &mut algorithm,
+                &mut protocol,
+                &mut channels,
+            );
+            assert_ne!(ret, ncclResult_t::ncclSuccess);
+        }
+    }
 }
@@ -44,7 +44,11 @@ impl log::Log for Logger {
                     level,
                     u64::MAX,
                     file.as_ptr(),
-                    record.line().unwrap_or_default().try_into().unwrap(),
+                    record
+                        .line()
+                        .unwrap_or_default()
+                        .try_into()
+                        .unwrap_or_default(),
                     args.as_ptr(),
                 );
             }
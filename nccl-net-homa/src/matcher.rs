@@ -0,0 +1,344 @@
+use crate::error::{Error, Result};
+use crate::wire::Header;
+use std::collections::{HashMap, VecDeque};
+
+/// A posted receive buffer, waiting for a message carrying its tag.
+struct Slot {
+    request: u64,
+    index: usize,
+    buffer: *mut u8,
+    capacity: usize,
+    tag: i32,
+}
+
+/// Where the fragments of a message are placed.
+enum Dest {
+    /// In place into a posted buffer.
+    Slot {
+        request: u64,
+        index: usize,
+        buffer: *mut u8,
+    },
+    /// Into a staging buffer, until a receive is posted for the message.
+    Buffered(Vec<u8>),
+    /// Nowhere, its receive was cancelled.
+    Discarded,
+}
+
+struct Message {
+    tag: i32,
+    length: usize,
+    received: usize,
+    dest: Dest,
+}
+
+/// Matches the fragments arriving on a connection to posted receives.
+///
+/// Messages are matched by tag in the order they were sent, fragments land in place in the
+/// matched buffer. Messages without a posted receive are staged until one is posted.
+#[derive(Default)]
+pub struct Matcher {
+    next_request: u64,
+    /// The next message to match, all before it were matched already.
+    next_seq: u32,
+    /// Buffers not matched to a message yet, in posting order.
+    posted: VecDeque<Slot>,
+    /// Messages with fragments still outstanding or without a posted receive.
+    messages: HashMap<u32, Message>,
+    /// Messages in send order that found no posted receive.
+    unexpected: VecDeque<u32>,
+    /// Sizes of the filled buffers.
+    completed: HashMap<(u64, usize), usize>,
+}
+
+// The buffers are NCCL's, lent for the duration of a receive, which NCCL may progress from
+// any thread.
+unsafe impl Send for Matcher {}
+
+impl Matcher {
+    /// Posts a grouped receive of one message per buffer, returning its id. Posts nothing if
+    /// a held back message does not fit the buffer it matches.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must stay valid until the receive is taken or cancelled.
+    pub unsafe fn post(&mut self, buffers: &mut [(&mut [u8], i32)]) -> Result<u64> {
+        // Matches every buffer before attaching any, a failure leaves nothing of the request.
+        let mut matched: Vec<Option<u32>> = Vec::with_capacity(buffers.len());
+        for (buffer, tag) in buffers.iter() {
+            let seq = self
+                .unexpected
+                .iter()
+                .copied()
+                .find(|seq| !matched.contains(&Some(*seq)) && self.messages[seq].tag == *tag);
+            if let Some(seq) = seq {
+                fits(&self.messages[&seq], buffer.len())?;
+            }
+            matched.push(seq);
+        }
+
+        let request = self.next_request;
+        self.next_request += 1;
+
+        for (index, ((buffer, tag), seq)) in buffers.iter_mut().zip(matched).enumerate() {
+            let slot = Slot {
+                request,
+                index,
+                buffer: buffer.as_mut_ptr(),
+                capacity: buffer.len(),
+                tag: *tag,
+            };
+
+            match seq {
+                Some(seq) => {
+                    self.unexpected.retain(|unexpected| *unexpected != seq);
+                    self.attach(seq, slot)?;
+                }
+                None => self.posted.push_back(slot),
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// Returns the sizes of the received messages once all buffers of `request` are filled.
+    pub fn take(&mut self, request: u64, count: usize) -> Option<Vec<usize>> {
+        if !(0..count).all(|index| self.completed.contains_key(&(request, index))) {
+            return None;
+        }
+
+        Some(
+            (0..count)
+                .map(|index| self.completed.remove(&(request, index)).unwrap())
+                .collect(),
+        )
+    }
+
+    /// Forgets a receive, fragments of messages matched to it are dropped.
+    pub fn cancel(&mut self, request: u64) {
+        self.posted.retain(|slot| slot.request != request);
+        self.completed.retain(|(r, _), _| *r != request);
+
+        for message in self.messages.values_mut() {
+            if matches!(message.dest, Dest::Slot { request: r, .. } if r == request) {
+                message.dest = Dest::Discarded;
+            }
+        }
+    }
+
+    /// Places an incoming fragment.
+    pub fn receive(&mut self, header: Header, payload: &[u8]) -> Result<()> {
+        let length = header.length as usize;
+        let offset = header.offset as usize;
+
+        if !self.messages.contains_key(&header.seq) {
+            if header.seq.wrapping_sub(self.next_seq) > u32::MAX / 2 {
+                log::warn!("fragment of completed message {}", header.seq);
+                return Err(Error::Internal);
+            }
+
+            self.messages.insert(
+                header.seq,
+                Message {
+                    tag: header.tag,
+                    length,
+                    received: 0,
+                    // Only allocated if the message cannot be matched right away.
+                    dest: Dest::Buffered(vec![]),
+                },
+            );
+        }
+
+        let message = &self.messages[&header.seq];
+        if message.tag != header.tag || message.length != length || offset + payload.len() > length
+        {
+            log::warn!("malformed fragment {:?}", header);
+            return Err(Error::Internal);
+        }
+
+        self.match_in_order()?;
+
+        if let Some(message) = self.messages.get_mut(&header.seq) {
+            match &mut message.dest {
+                Dest::Slot { buffer, .. } => unsafe {
+                    buffer
+                        .add(offset)
+                        .copy_from_nonoverlapping(payload.as_ptr(), payload.len());
+                },
+                Dest::Buffered(staging) => {
+                    staging.resize(length, 0);
+                    staging[offset..offset + payload.len()].copy_from_slice(payload)
+                }
+                Dest::Discarded => {}
+            }
+            message.received += payload.len();
+
+            self.complete(header.seq);
+        }
+
+        Ok(())
+    }
+
+    /// Matches the messages next in send order that have started arriving.
+    fn match_in_order(&mut self) -> Result<()> {
+        while let Some(message) = self.messages.get(&self.next_seq) {
+            let seq = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+
+            match self.posted.iter().position(|slot| slot.tag == message.tag) {
+                Some(position) => {
+                    let slot = self.posted.remove(position).unwrap();
+                    self.attach(seq, slot)?;
+                }
+                None => {
+                    log::debug!("holding back message {} with tag {}", seq, message.tag);
+                    self.unexpected.push_back(seq);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Redirects message `seq` into a posted buffer, along with what was staged so far.
+    fn attach(&mut self, seq: u32, slot: Slot) -> Result<()> {
+        let message = self.messages.get_mut(&seq).unwrap();
+        fits(message, slot.capacity)?;
+
+        if let Dest::Buffered(staging) = &message.dest {
+            unsafe {
+                slot.buffer
+                    .copy_from_nonoverlapping(staging.as_ptr(), staging.len())
+            };
+        }
+
+        message.dest = Dest::Slot {
+            request: slot.request,
+            index: slot.index,
+            buffer: slot.buffer,
+        };
+
+        self.complete(seq);
+
+        Ok(())
+    }
+
+    /// Retires message `seq` if all of its fragments landed in a posted buffer.
+    fn complete(&mut self, seq: u32) {
+        let message = &self.messages[&seq];
+
+        if message.received < message.length {
+            return;
+        }
+
+        match message.dest {
+            Dest::Slot { request, index, .. } => {
+                self.completed.insert((request, index), message.length);
+            }
+            Dest::Discarded => {}
+            Dest::Buffered(_) => return,
+        }
+
+        self.messages.remove(&seq);
+    }
+}
+
+/// Fails with [`Error::InvalidUsage`] if `message` does not fit `capacity` bytes.
+fn fits(message: &Message, capacity: usize) -> Result<()> {
+    if message.length > capacity {
+        log::warn!(
+            "message of {} bytes exceeds the {} byte buffer",
+            message.length,
+            capacity
+        );
+        return Err(Error::InvalidUsage);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::matcher::*;
+    use crate::wire::Kind;
+
+    fn fragment(tag: i32, seq: u32, offset: u32, length: u32) -> Header {
+        Header {
+            kind: Kind::Data,
+            tag,
+            conn: 0,
+            seq,
+            offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn reassemble() {
+        let mut matcher = Matcher::default();
+
+        let mut a = vec![0u8; 8];
+        let mut b = vec![0u8; 8];
+        let request = unsafe { matcher.post(&mut [(&mut a, 1), (&mut b, 2)]).unwrap() };
+
+        // Message 1 overtakes message 0, both tagged 2, the first fragment of message 0
+        // arrives only after the receive got its buffer.
+        matcher.receive(fragment(2, 1, 0, 2), b"xx").unwrap();
+        matcher.receive(fragment(2, 0, 4, 6), b"ef").unwrap();
+        assert_eq!(matcher.take(request, 2), None);
+        matcher.receive(fragment(1, 2, 0, 3), b"abc").unwrap();
+        matcher.receive(fragment(2, 0, 0, 6), b"abcd").unwrap();
+
+        assert_eq!(matcher.take(request, 2), Some(vec![3, 6]));
+        assert_eq!(&a[..3], b"abc");
+        assert_eq!(&b[..6], b"abcdef");
+
+        // Message 1 was held back, the next receive for its tag picks it up.
+        let mut c = vec![0u8; 2];
+        let request = unsafe { matcher.post(&mut [(&mut c, 2)]).unwrap() };
+        assert_eq!(matcher.take(request, 1), Some(vec![2]));
+        assert_eq!(&c, b"xx");
+
+        let mut d = vec![0u8; 1];
+        let request = unsafe { matcher.post(&mut [(&mut d, 3)]).unwrap() };
+        assert!(matches!(
+            matcher.receive(fragment(3, 3, 0, 2), b"yy"),
+            Err(Error::InvalidUsage)
+        ));
+        matcher.cancel(request);
+    }
+
+    #[test]
+    fn failed_group() {
+        let mut matcher = Matcher::default();
+
+        matcher.receive(fragment(1, 0, 0, 3), b"abc").unwrap();
+        matcher.receive(fragment(2, 1, 0, 6), b"abcdef").unwrap();
+
+        // The first buffer is posted, the second matches message 0, the third is too small
+        // for message 1.
+        let mut a = vec![0u8; 8];
+        let mut b = vec![0u8; 8];
+        let mut x = vec![0u8; 2];
+        assert!(matches!(
+            unsafe { matcher.post(&mut [(&mut a, 3), (&mut b, 1), (&mut x, 2)]) },
+            Err(Error::InvalidUsage)
+        ));
+        assert_eq!(b, [0; 8]);
+
+        let mut c = vec![0u8; 8];
+        let mut d = vec![0u8; 8];
+        let mut e = vec![0u8; 8];
+        let request = unsafe {
+            matcher
+                .post(&mut [(&mut c, 1), (&mut d, 2), (&mut e, 3)])
+                .unwrap()
+        };
+        matcher.receive(fragment(3, 2, 0, 1), b"z").unwrap();
+
+        assert_eq!(matcher.take(request, 3), Some(vec![3, 6, 1]));
+        assert_eq!(&c[..3], b"abc");
+        assert_eq!(&d[..6], b"abcdef");
+        assert_eq!(&e[..1], b"z");
+        assert_eq!(a, [0; 8]);
+    }
+}
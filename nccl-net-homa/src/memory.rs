@@ -0,0 +1,215 @@
+//! Memory registration, see [`register`].
+//!
+//! Homa copies out of and into any host memory, a registration only records the range,
+//! pins it with `NCCL_HOMA_MLOCK=1` and lets sends and receives check their buffers.
+
+use crate::config;
+use crate::error::{Error, Result};
+use nccl_net_sys::NCCL_PTR_HOST;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+
+/// The region of every live registration, by start and end address.
+static REGIONS: Mutex<BTreeMap<(usize, usize), Weak<Region>>> = Mutex::new(BTreeMap::new());
+
+/// A registered range, shared by every registration of it.
+#[derive(Debug)]
+pub struct Region {
+    range: Range<usize>,
+    type_: i32,
+    locked: bool,
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        let mut regions = REGIONS.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // A registration of the same range racing with this drop may have replaced it.
+        let key = (self.range.start, self.range.end);
+        if regions
+            .get(&key)
+            .is_some_and(|region| region.strong_count() == 0)
+        {
+            regions.remove(&key);
+        }
+
+        if !self.locked {
+            return;
+        }
+
+        // Locks do not nest, the pages shared with other live regions stay locked.
+        let mut unlock = vec![pages(&self.range)];
+        for (&(start, end), region) in regions.iter() {
+            if region.strong_count() == 0 {
+                continue;
+            }
+            let other = pages(&(start..end));
+            unlock = unlock
+                .into_iter()
+                .flat_map(|range| {
+                    [
+                        range.start..range.end.min(other.start),
+                        range.start.max(other.end)..range.end,
+                    ]
+                })
+                .filter(|range| !range.is_empty())
+                .collect();
+        }
+
+        for range in unlock {
+            if unsafe { libc::munlock(range.start as *const c_void, range.len()) } != 0 {
+                log::warn!(
+                    "failed to unlock {:#x?}: {}",
+                    range,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
+
+/// The pages `range` touches.
+fn pages(range: &Range<usize>) -> Range<usize> {
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    range.start / page * page..range.end.div_ceil(page) * page
+}
+
+/// What `regMr` hands to NCCL, counting the requests using it.
+#[derive(Debug)]
+pub struct MemoryHandle {
+    region: Arc<Region>,
+    users: AtomicUsize,
+}
+
+impl MemoryHandle {
+    pub fn range(&self) -> Range<usize> {
+        self.region.range.clone()
+    }
+
+    pub fn type_(&self) -> i32 {
+        self.region.type_
+    }
+
+    /// Marks the handle as used by a request on `data`, which must lie within it.
+    pub fn acquire(&self, data: &[u8]) -> Result<InUse<'_>> {
+        let start = data.as_ptr() as usize;
+        let range = &self.region.range;
+
+        // Empty buffers may come with any pointer.
+        if !data.is_empty() && (start < range.start || start + data.len() > range.end) {
+            log::warn!(
+                "buffer {:#x}+{} outside of registration {:#x?}",
+                start,
+                data.len(),
+                range
+            );
+            return Err(Error::InvalidArgument);
+        }
+
+        self.users.fetch_add(1, Ordering::Relaxed);
+        Ok(InUse(self))
+    }
+}
+
+/// A request's use of a [`MemoryHandle`], ends when the request is dropped.
+pub struct InUse<'a>(&'a MemoryHandle);
+
+impl<'a> Drop for InUse<'a> {
+    fn drop(&mut self) {
+        self.0.users.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Registers `size` bytes of host memory at address `start`, sharing the region of a live
+/// registration of the same range.
+pub fn register(start: usize, size: usize, type_: i32) -> Result<MemoryHandle> {
+    if type_ != NCCL_PTR_HOST as i32 {
+        log::warn!("cannot register memory of type {}", type_);
+        return Err(Error::InvalidArgument);
+    }
+
+    let end = start.checked_add(size).ok_or(Error::InvalidArgument)?;
+
+    let mut regions = REGIONS.lock()?;
+
+    let region = match regions.get(&(start, end)).and_then(Weak::upgrade) {
+        Some(region) => region,
+        None => {
+            let locked = config::get().mlock && size > 0;
+            if locked && unsafe { libc::mlock(start as *const c_void, size) } != 0 {
+                let err = std::io::Error::last_os_error();
+                log::warn!("failed to lock {:#x}+{}: {}", start, size, err);
+                return Err(err.into());
+            }
+
+            let region = Arc::new(Region {
+                range: start..end,
+                type_,
+                locked,
+            });
+            regions.insert((start, end), Arc::downgrade(&region));
+            region
+        }
+    };
+
+    Ok(MemoryHandle {
+        region,
+        users: AtomicUsize::new(0),
+    })
+}
+
+/// Refuses while requests are using `mhandle`.
+pub fn deregister(mhandle: &MemoryHandle) -> Result<()> {
+    let users = mhandle.users.load(Ordering::Relaxed);
+    if users != 0 {
+        log::warn!(
+            "cannot deregister {:#x?}, {} requests are using it",
+            mhandle.region.range,
+            users
+        );
+        return Err(Error::InvalidUsage);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory::*;
+
+    #[test]
+    fn registration() {
+        let buf = vec![0u8; 1 << 16];
+        let data = buf.as_ptr() as usize;
+
+        let first = register(data, buf.len(), NCCL_PTR_HOST as i32).unwrap();
+        let second = register(data, buf.len(), NCCL_PTR_HOST as i32).unwrap();
+        assert!(Arc::ptr_eq(&first.region, &second.region));
+        assert_eq!(first.range().len(), buf.len());
+
+        {
+            let _in_use = first.acquire(&buf[100..200]).unwrap();
+            assert!(matches!(deregister(&first), Err(Error::InvalidUsage)));
+            deregister(&second).unwrap();
+        }
+        deregister(&first).unwrap();
+
+        let inner = register(data, 10, NCCL_PTR_HOST as i32).unwrap();
+        assert!(matches!(
+            inner.acquire(&buf[5..20]),
+            Err(Error::InvalidArgument)
+        ));
+        inner.acquire(&[]).unwrap();
+
+        assert!(matches!(
+            register(data, buf.len(), nccl_net_sys::NCCL_PTR_CUDA as i32),
+            Err(Error::InvalidArgument)
+        ));
+
+        drop((first, second));
+        let regions = REGIONS.lock().unwrap();
+        assert!(!regions.contains_key(&(data, data + buf.len())));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::error::{Error, Result};
+use nccl_net_sys::{ncclDataType_t, ncclRedOp_t};
+
+/// The element types the aggregator reduces, with the values of `ncclDataType_t`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    I8 = 0,
+    U8 = 1,
+    I32 = 2,
+    U32 = 3,
+    I64 = 4,
+    U64 = 5,
+    F16 = 6,
+    F32 = 7,
+    F64 = 8,
+    Bf16 = 9,
+}
+
+impl DataType {
+    pub fn size(self) -> usize {
+        match self {
+            DataType::I8 | DataType::U8 => 1,
+            DataType::F16 | DataType::Bf16 => 2,
+            DataType::I32 | DataType::U32 | DataType::F32 => 4,
+            DataType::I64 | DataType::U64 | DataType::F64 => 8,
+        }
+    }
+}
+
+impl TryFrom<u32> for DataType {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        Ok(match value {
+            0 => DataType::I8,
+            1 => DataType::U8,
+            2 => DataType::I32,
+            3 => DataType::U32,
+            4 => DataType::I64,
+            5 => DataType::U64,
+            6 => DataType::F16,
+            7 => DataType::F32,
+            8 => DataType::F64,
+            9 => DataType::Bf16,
+            _ => return Err(Error::InvalidArgument),
+        })
+    }
+}
+
+impl TryFrom<ncclDataType_t> for DataType {
+    type Error = Error;
+
+    fn try_from(value: ncclDataType_t) -> Result<Self> {
+        value.0.try_into()
+    }
+}
+
+/// The reductions the aggregator performs, with the values of `ncclRedOp_t`. Averages and
+/// ops created by `ncclRedOpCreatePreMulSum` are left to NCCL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Sum = 0,
+    Prod = 1,
+    Max = 2,
+    Min = 3,
+}
+
+impl TryFrom<u32> for Op {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        Ok(match value {
+            0 => Op::Sum,
+            1 => Op::Prod,
+            2 => Op::Max,
+            3 => Op::Min,
+            _ => return Err(Error::InvalidArgument),
+        })
+    }
+}
+
+impl TryFrom<ncclRedOp_t> for Op {
+    type Error = Error;
+
+    fn try_from(value: ncclRedOp_t) -> Result<Self> {
+        value.0.try_into()
+    }
+}
+
+macro_rules! integer {
+    ($acc:expr, $src:expr, $op:expr, $t:ty) => {
+        combine::<{ std::mem::size_of::<$t>() }>($acc, $src, |a, b| {
+            let (a, b) = (<$t>::from_ne_bytes(a), <$t>::from_ne_bytes(b));
+            match $op {
+                Op::Sum => a.wrapping_add(b),
+                Op::Prod => a.wrapping_mul(b),
+                Op::Max => a.max(b),
+                Op::Min => a.min(b),
+            }
+            .to_ne_bytes()
+        })
+    };
+}
+
+macro_rules! float {
+    ($acc:expr, $src:expr, $op:expr, $n:literal, $from:expr, $to:expr) => {
+        combine::<$n>($acc, $src, |a, b| {
+            let (a, b) = ($from(a), $from(b));
+            $to(match $op {
+                Op::Sum => a + b,
+                Op::Prod => a * b,
+                Op::Max => a.max(b),
+                Op::Min => a.min(b),
+            })
+        })
+    };
+}
+
+/// Combines `src` into `acc` element by element, both hold whole elements of `data_type`.
+pub fn reduce(data_type: DataType, op: Op, acc: &mut [u8], src: &[u8]) {
+    debug_assert_eq!(acc.len(), src.len());
+    debug_assert_eq!(acc.len() % data_type.size(), 0);
+
+    match data_type {
+        DataType::I8 => integer!(acc, src, op, i8),
+        DataType::U8 => integer!(acc, src, op, u8),
+        DataType::I32 => integer!(acc, src, op, i32),
+        DataType::U32 => integer!(acc, src, op, u32),
+        DataType::I64 => integer!(acc, src, op, i64),
+        DataType::U64 => integer!(acc, src, op, u64),
+        DataType::F32 => float!(acc, src, op, 4, f32::from_ne_bytes, f32::to_ne_bytes),
+        DataType::F64 => float!(acc, src, op, 8, f64::from_ne_bytes, f64::to_ne_bytes),
+        // Half precision is reduced in single precision and rounded back after every step.
+        DataType::F16 => float!(
+            acc,
+            src,
+            op,
+            2,
+            |a| f16_to_f32(u16::from_ne_bytes(a)),
+            |a| f32_to_f16(a).to_ne_bytes()
+        ),
+        DataType::Bf16 => float!(
+            acc,
+            src,
+            op,
+            2,
+            |a| bf16_to_f32(u16::from_ne_bytes(a)),
+            |a| f32_to_bf16(a).to_ne_bytes()
+        ),
+    }
+}
+
+fn combine<const N: usize>(acc: &mut [u8], src: &[u8], f: impl Fn([u8; N], [u8; N]) -> [u8; N]) {
+    for (a, b) in acc.chunks_exact_mut(N).zip(src.chunks_exact(N)) {
+        let c = f(a.try_into().unwrap(), b.try_into().unwrap());
+        a.copy_from_slice(&c);
+    }
+}
+
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = u32::from((h >> 10) & 0x1f);
+    let man = u32::from(h & 0x3ff);
+
+    match exp {
+        0 => sign * man as f32 / (1 << 24) as f32,
+        0x1f => f32::from_bits(u32::from(h & 0x8000) << 16 | 0x7f80_0000 | man << 13),
+        _ => f32::from_bits(u32::from(h & 0x8000) << 16 | (exp + 112) << 23 | man << 13),
+    }
+}
+
+/// Rounds to nearest, ties to even.
+fn f32_to_f16(f: f32) -> u16 {
+    let bits = f.to_bits();
+    let sign = (bits >> 16) as u16 & 0x8000;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let man = bits & 0x7f_ffff;
+
+    if exp == 0xff {
+        let nan = if man != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let exp = exp - 127 + 15;
+    if exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    // A carry out of the mantissa moves on to the next exponent, or to infinity.
+    let (value, shift) = if exp > 0 {
+        ((exp as u32) << 23 | man, 13)
+    } else if exp >= -10 {
+        (man | 0x80_0000, (14 - exp) as u32)
+    } else {
+        return sign;
+    };
+
+    let half = 1 << (shift - 1);
+    let rest = value & ((1 << shift) - 1);
+    let mut rounded = value >> shift;
+    if rest > half || (rest == half && rounded & 1 == 1) {
+        rounded += 1;
+    }
+
+    sign | rounded as u16
+}
+
+fn bf16_to_f32(h: u16) -> f32 {
+    f32::from_bits(u32::from(h) << 16)
+}
+
+/// Rounds to nearest, ties to even.
+fn f32_to_bf16(f: f32) -> u16 {
+    let bits = f.to_bits();
+    if f.is_nan() {
+        return (bits >> 16) as u16 | 0x40;
+    }
+    let rounding = 0x7fff + ((bits >> 16) & 1);
+    (bits.wrapping_add(rounding) >> 16) as u16
+}
+
+#[cfg(test)]
+mod test {
+    use crate::reduce::*;
+
+    fn bytes<const N: usize, T>(values: &[T], f: impl Fn(&T) -> [u8; N]) -> Vec<u8> {
+        values.iter().flat_map(f).collect()
+    }
+
+    #[test]
+    fn integers() {
+        let mut acc = bytes(&[1i32, -5, i32::MAX], |v| v.to_ne_bytes());
+        let src = bytes(&[2i32, 3, 1], |v| v.to_ne_bytes());
+
+        reduce(DataType::I32, Op::Sum, &mut acc, &src);
+        assert_eq!(acc, bytes(&[3i32, -2, i32::MIN], |v| v.to_ne_bytes()));
+
+        reduce(DataType::I32, Op::Max, &mut acc, &src);
+        assert_eq!(acc, bytes(&[3i32, 3, 1], |v| v.to_ne_bytes()));
+
+        let mut acc = vec![200u8, 3];
+        reduce(DataType::U8, Op::Prod, &mut acc, &[2, 4]);
+        assert_eq!(acc, [144, 12]);
+    }
+
+    #[test]
+    fn floats() {
+        let mut acc = bytes(&[1.5f64, -2.0], |v| v.to_ne_bytes());
+        reduce(
+            DataType::F64,
+            Op::Min,
+            &mut acc,
+            &bytes(&[0.5f64, 4.0], |v| v.to_ne_bytes()),
+        );
+        assert_eq!(acc, bytes(&[0.5f64, -2.0], |v| v.to_ne_bytes()));
+
+        let mut acc = bytes(&[1.0f32, 2.5], |v| f32_to_f16(*v).to_ne_bytes());
+        reduce(
+            DataType::F16,
+            Op::Sum,
+            &mut acc,
+            &bytes(&[0.25f32, -3.0], |v| f32_to_f16(*v).to_ne_bytes()),
+        );
+        assert_eq!(
+            acc,
+            bytes(&[1.25f32, -0.5], |v| f32_to_f16(*v).to_ne_bytes())
+        );
+
+        let mut acc = bytes(&[3.0f32], |v| f32_to_bf16(*v).to_ne_bytes());
+        reduce(
+            DataType::Bf16,
+            Op::Prod,
+            &mut acc,
+            &bytes(&[-0.5f32], |v| f32_to_bf16(*v).to_ne_bytes()),
+        );
+        assert_eq!(acc, bytes(&[-1.5f32], |v| f32_to_bf16(*v).to_ne_bytes()));
+    }
+
+    #[test]
+    fn half() {
+        for (f, h) in [
+            (0.0f32, 0x0000),
+            (-2.0, 0xc000),
+            (65504.0, 0x7bff),
+            (1e6, 0x7c00),
+            (f32::NEG_INFINITY, 0xfc00),
+            // The smallest subnormal, and a value rounding to it.
+            (5.960_464_5e-8, 0x0001),
+            (4e-8, 0x0001),
+            (2e-8, 0x0000),
+            // Ties round to even.
+            (1.0 + 1.0 / 2048.0, 0x3c00),
+            (1.0 + 3.0 / 2048.0, 0x3c02),
+        ] {
+            assert_eq!(f32_to_f16(f), h, "{}", f);
+        }
+
+        for h in [0x0001u16, 0x03ff, 0x3c00, 0x7bff, 0x8400, 0xfc00] {
+            assert_eq!(f32_to_f16(f16_to_f32(h)), h);
+        }
+        assert!(f16_to_f32(0x7e00).is_nan());
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+
+        assert_eq!(f32_to_bf16(1.0), 0x3f80);
+        assert_eq!(f32_to_bf16(1.0 + 1.0 / 256.0), 0x3f80);
+        assert_eq!(f32_to_bf16(1.0 + 3.0 / 256.0), 0x3f82);
+        assert_eq!(bf16_to_f32(0xc0a0), -5.0);
+        assert!(bf16_to_f32(f32_to_bf16(f32::NAN)).is_nan());
+    }
+}
@@ -0,0 +1,374 @@
+//! A tuner plugin choosing algorithm, protocol and channels by collective and size.
+//!
+//! The choices come from a [`Table`], read from `NCCL_HOMA_TUNER_FILE` or [`DEFAULT`], NCCL
+//! decides whatever no rule covers.
+
+use crate::config;
+use crate::error::{Error, Result};
+use log::LevelFilter;
+use nccl_net_sys::tuner::{Collective, Selection, TunerPlugin};
+use nccl_net_sys::*;
+use std::ffi::c_int;
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The table used without `NCCL_HOMA_TUNER_FILE`, which leaves every choice to NCCL. A table
+/// for a cluster is [`derive`]d by `homa-tune` from round trips measured between its nodes.
+pub const DEFAULT: &str = "\
+# collective, min bytes, max bytes, algorithm, protocol, channels
+";
+
+/// Applies to the collectives of one kind within a range of sizes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub func: ncclFunc_t,
+    pub bytes: Range<usize>,
+    /// Only applies to comms spanning this many nodes.
+    pub nodes: Option<usize>,
+    pub selection: Selection,
+}
+
+impl Rule {
+    fn matches(&self, collective: &Collective, nodes: usize) -> bool {
+        let supported = match self.selection.algorithm {
+            Some((algorithm, _)) => match algorithm as u32 {
+                NCCL_ALGO_COLLNET_DIRECT | NCCL_ALGO_COLLNET_CHAIN => collective.collnet_support,
+                NCCL_ALGO_NVLS | NCCL_ALGO_NVLS_TREE => collective.nvls_support,
+                _ => true,
+            },
+            None => true,
+        };
+
+        supported
+            && self.func == collective.func
+            && self.bytes.contains(&collective.bytes)
+            && self.nodes.unwrap_or(nodes) == nodes
+    }
+}
+
+impl FromStr for Rule {
+    type Err = ();
+
+    /// `collective, min bytes, max bytes, algorithm, protocol, channels[, nodes]`, -1 leaves
+    /// the maximum, the channels or the nodes open, `default` the algorithm and protocol.
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let [func, min, max, algorithm, protocol, channels, rest @ ..] = &fields[..] else {
+            return Err(());
+        };
+
+        let func = match *func {
+            "broadcast" => ncclFunc_t::ncclFuncBroadcast,
+            "reduce" => ncclFunc_t::ncclFuncReduce,
+            "allgather" => ncclFunc_t::ncclFuncAllGather,
+            "reducescatter" => ncclFunc_t::ncclFuncReduceScatter,
+            "allreduce" => ncclFunc_t::ncclFuncAllReduce,
+            _ => return Err(()),
+        };
+
+        let min = min.parse().map_err(|_| ())?;
+        let max = match *max {
+            "-1" => usize::MAX,
+            max => max.parse().map_err(|_| ())?,
+        };
+
+        let algorithm = match *algorithm {
+            "default" => None,
+            "tree" => Some(NCCL_ALGO_TREE),
+            "ring" => Some(NCCL_ALGO_RING),
+            "collnet_direct" => Some(NCCL_ALGO_COLLNET_DIRECT),
+            "collnet_chain" => Some(NCCL_ALGO_COLLNET_CHAIN),
+            "nvls" => Some(NCCL_ALGO_NVLS),
+            "nvls_tree" => Some(NCCL_ALGO_NVLS_TREE),
+            _ => return Err(()),
+        };
+        let protocol = match *protocol {
+            "default" => None,
+            "ll" => Some(NCCL_PROTO_LL),
+            "ll128" => Some(NCCL_PROTO_LL128),
+            "simple" => Some(NCCL_PROTO_SIMPLE),
+            _ => return Err(()),
+        };
+        let algorithm = match (algorithm, protocol) {
+            (Some(algorithm), Some(protocol)) => Some((algorithm as c_int, protocol as c_int)),
+            (None, None) => None,
+            // NCCL takes both or neither.
+            _ => return Err(()),
+        };
+
+        let channels = match channels.parse().map_err(|_| ())? {
+            -1 => None,
+            n if n > 0 => Some(n),
+            _ => return Err(()),
+        };
+
+        let nodes = match rest {
+            [] | ["-1"] => None,
+            [nodes] => Some(nodes.parse().map_err(|_| ())?),
+            _ => return Err(()),
+        };
+
+        Ok(Self {
+            func,
+            bytes: min..max,
+            nodes,
+            selection: Selection {
+                algorithm,
+                channels,
+            },
+        })
+    }
+}
+
+/// Rules by precedence, the first matching one applies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub rules: Vec<Rule>,
+}
+
+impl Table {
+    pub fn select(&self, collective: &Collective, nodes: usize) -> Selection {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(collective, nodes))
+            .map(|rule| rule.selection)
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for Table {
+    type Err = Error;
+
+    /// One [`Rule`] per line, `#` starts a comment.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut rules = vec![];
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let rule = line.parse().map_err(|()| {
+                log::warn!("tuner: invalid rule on line {}: {:?}", number + 1, line);
+                Error::InvalidArgument
+            })?;
+            rules.push(rule);
+        }
+
+        Ok(Self { rules })
+    }
+}
+
+/// Derives a table in the syntax of [`DEFAULT`] from `(bytes, round trip)` samples of single
+/// RPCs, as measured by `homa-tune`, sorted by size.
+///
+/// A message is bound by latency while its round trip stays within twice that of the
+/// smallest, LL is chosen below the first size past that and Simple from it on. Tree takes
+/// fewer steps than ring but sends whole messages in each, for allreduce it is chosen while
+/// the round trip exceeds that of the smallest by at most a quarter. Past the samples Simple
+/// is assumed.
+pub fn derive(samples: &[(usize, Duration)]) -> String {
+    let Some(&(_, base)) = samples.first() else {
+        return String::new();
+    };
+    let beyond = samples.last().map_or(0, |&(bytes, _)| bytes * 2);
+    let limit = |factor: f64| {
+        samples
+            .iter()
+            .find(|(_, latency)| latency.as_secs_f64() > base.as_secs_f64() * factor)
+            .map_or(beyond, |&(bytes, _)| bytes)
+    };
+    let (tree, ll) = (limit(1.25), limit(2.0));
+
+    let mut table =
+        String::from("# collective, min bytes, max bytes, algorithm, protocol, channels\n");
+    let mut rule = |func: &str, min: usize, max: Option<usize>, algorithm: &str, protocol| {
+        if min < max.unwrap_or(usize::MAX) {
+            let max = max.map_or("-1".to_string(), |max| max.to_string());
+            table += &format!("{func}, {min}, {max}, {algorithm}, {protocol}, -1\n");
+        }
+    };
+    rule("allreduce", 0, Some(tree), "tree", "ll");
+    rule("allreduce", tree, Some(ll), "ring", "ll");
+    rule("allreduce", ll, None, "ring", "simple");
+    for func in ["broadcast", "reduce", "allgather", "reducescatter"] {
+        rule(func, 0, Some(ll), "ring", "ll");
+        rule(func, ll, None, "ring", "simple");
+    }
+    table
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        DEFAULT.parse().unwrap()
+    }
+}
+
+pub struct Context {
+    nodes: usize,
+    table: Table,
+}
+
+pub struct HomaTuner {}
+
+impl TunerPlugin for HomaTuner {
+    type Context = Context;
+    type Error = Error;
+
+    fn init(_nranks: usize, nodes: usize, logger: ncclDebugLogger_t) -> Result<Context> {
+        // Like the net plugin's init, which may or may not have run before.
+        if crate::logger::Logger::init(LevelFilter::Debug, logger).is_err() {
+            log::debug!("logger already installed");
+        }
+        config::init()?;
+        log::set_max_level(config::get().log_level);
+
+        let table = match config::get().tuner {
+            Some(path) => std::fs::read_to_string(&path)
+                .map_err(|err| {
+                    log::warn!("tuner: failed to read {}: {}", path.display(), err);
+                    err
+                })?
+                .parse()?,
+            None => Table::default(),
+        };
+
+        Ok(Context { nodes, table })
+    }
+
+    fn get_coll_info(context: &Context, collective: &Collective) -> Result<Selection> {
+        // Within a node Homa carries nothing.
+        if context.nodes <= 1 {
+            return Ok(Selection::default());
+        }
+        Ok(context.table.select(collective, context.nodes))
+    }
+
+    fn destroy(_context: Context) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tuner::*;
+
+    fn allreduce(bytes: usize) -> Collective {
+        Collective {
+            func: ncclFunc_t::ncclFuncAllReduce,
+            bytes,
+            collnet_support: false,
+            nvls_support: false,
+            pipe_ops: 1,
+        }
+    }
+
+    fn selection(algorithm: u32, protocol: u32) -> Selection {
+        Selection {
+            algorithm: Some((algorithm as c_int, protocol as c_int)),
+            channels: None,
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let table = Table::default();
+
+        for bytes in [0, 1024, 1 << 30] {
+            assert_eq!(table.select(&allreduce(bytes), 2), Selection::default());
+        }
+    }
+
+    #[test]
+    fn derived() {
+        let samples = [
+            (64, Duration::from_micros(10)),
+            (128, Duration::from_micros(12)),
+            (256, Duration::from_micros(15)),
+            (512, Duration::from_micros(30)),
+        ];
+        assert_eq!(
+            derive(&samples).parse::<Table>().unwrap(),
+            "allreduce, 0, 256, tree, ll, -1
+             allreduce, 256, 512, ring, ll, -1
+             allreduce, 512, -1, ring, simple, -1
+             broadcast, 0, 512, ring, ll, -1
+             broadcast, 512, -1, ring, simple, -1
+             reduce, 0, 512, ring, ll, -1
+             reduce, 512, -1, ring, simple, -1
+             allgather, 0, 512, ring, ll, -1
+             allgather, 512, -1, ring, simple, -1
+             reducescatter, 0, 512, ring, ll, -1
+             reducescatter, 512, -1, ring, simple, -1"
+                .parse()
+                .unwrap()
+        );
+
+        // A round trip that doubles right away leaves LL to the smallest size, with tree.
+        let samples = [
+            (64, Duration::from_micros(10)),
+            (128, Duration::from_micros(30)),
+        ];
+        assert_eq!(
+            derive(&samples).parse::<Table>().unwrap(),
+            "allreduce, 0, 128, tree, ll, -1
+             allreduce, 128, -1, ring, simple, -1
+             broadcast, 0, 128, ring, ll, -1
+             broadcast, 128, -1, ring, simple, -1
+             reduce, 0, 128, ring, ll, -1
+             reduce, 128, -1, ring, simple, -1
+             allgather, 0, 128, ring, ll, -1
+             allgather, 128, -1, ring, simple, -1
+             reducescatter, 0, 128, ring, ll, -1
+             reducescatter, 128, -1, ring, simple, -1"
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let table: Table = "
+            # small allreduce over collnet where supported, else NCCL decides
+            allreduce, 0, 4096, collnet_direct, simple, 2, 4
+            allreduce, 0, -1, default, default, 8 # only channels
+        "
+        .parse()
+        .unwrap();
+
+        let collnet = Collective {
+            collnet_support: true,
+            ..allreduce(100)
+        };
+        assert_eq!(
+            table.select(&collnet, 4),
+            Selection {
+                channels: Some(2),
+                ..selection(NCCL_ALGO_COLLNET_DIRECT, NCCL_PROTO_SIMPLE)
+            }
+        );
+
+        let only_channels = Selection {
+            algorithm: None,
+            channels: Some(8),
+        };
+        assert_eq!(table.select(&allreduce(100), 4), only_channels);
+        assert_eq!(table.select(&collnet, 2), only_channels);
+
+        for table in [
+            "allreduce, 0, -1, ring, simple",
+            "allreduce, 0, -1, ring, default, -1",
+            "allreduce, 0, -1, ring, ll, 0",
+            "alltoall, 0, -1, ring, ll, -1",
+            "allreduce, 0, 1k, ring, ll, -1",
+            "allreduce, 0, -1, ring, ll, -1, 2, 3",
+        ] {
+            assert!(matches!(
+                table.parse::<Table>(),
+                Err(Error::InvalidArgument)
+            ));
+        }
+    }
+}
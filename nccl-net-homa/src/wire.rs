@@ -0,0 +1,225 @@
+use crate::error::{Error, Result};
+use std::ffi::{CStr, CString};
+use std::net::SocketAddr;
+
+/// What an RPC carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A fragment of a message sent by `isend`.
+    Data = 0,
+    /// The handshake of `connect`, addressed to a listener rather than a connection. Its
+    /// response carries the id of the new connection.
+    Connect = 1,
+}
+
+/// Prepended to every RPC sent to a listening socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub kind: Kind,
+    pub tag: i32,
+    /// The connection the fragment belongs to, see [`Handle`].
+    pub conn: u32,
+    /// Position of the message on its connection, Homa does not order RPCs.
+    pub seq: u32,
+    /// Where the fragment's payload goes in the message.
+    pub offset: u32,
+    /// The length of the whole message.
+    pub length: u32,
+}
+
+impl Header {
+    pub const LEN: usize = 24;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut header = [0; Self::LEN];
+        header[0..4].copy_from_slice(&self.tag.to_le_bytes());
+        header[4..8].copy_from_slice(&self.conn.to_le_bytes());
+        header[8..12].copy_from_slice(&self.seq.to_le_bytes());
+        header[12..16].copy_from_slice(&self.offset.to_le_bytes());
+        header[16..20].copy_from_slice(&self.length.to_le_bytes());
+        header[20..24].copy_from_slice(&(self.kind as u32).to_le_bytes());
+        header
+    }
+
+    /// Splits a received fragment into its header and payload.
+    pub fn decode(fragment: &[u8]) -> Result<(Self, &[u8])> {
+        if fragment.len() < Self::LEN {
+            log::warn!("fragment of {} bytes lacks a header", fragment.len());
+            return Err(Error::Internal);
+        }
+
+        let (header, payload) = fragment.split_at(Self::LEN);
+        let field = |i: usize| header[i..i + 4].try_into().unwrap();
+
+        let kind = match u32::from_le_bytes(field(20)) {
+            0 => Kind::Data,
+            1 => Kind::Connect,
+            kind => {
+                log::warn!("fragment of unknown kind {}", kind);
+                return Err(Error::Internal);
+            }
+        };
+
+        Ok((
+            Self {
+                kind,
+                tag: i32::from_le_bytes(field(0)),
+                conn: u32::from_le_bytes(field(4)),
+                seq: u32::from_le_bytes(field(8)),
+                offset: u32::from_le_bytes(field(12)),
+                length: u32::from_le_bytes(field(16)),
+            },
+            payload,
+        ))
+    }
+}
+
+/// Prepended to every chunk of an `iallreduce` sent to the aggregator.
+///
+/// Chunks are matched across the ranks of a group by their sequence number and offset, all
+/// ranks issue the same reductions in the same order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    /// Identifies the ranks reducing together, see [`crate::collnet`].
+    pub group: u64,
+    /// Where the chunk's payload lies in the reduced buffer.
+    pub offset: u64,
+    /// Position of the reduction on its group.
+    pub seq: u32,
+    pub nranks: u32,
+    pub rank: u32,
+    /// An `ncclDataType_t`.
+    pub data_type: u32,
+    /// An `ncclRedOp_t`.
+    pub op: u32,
+}
+
+impl Chunk {
+    pub const LEN: usize = 36;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut chunk = [0; Self::LEN];
+        chunk[0..8].copy_from_slice(&self.group.to_le_bytes());
+        chunk[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        chunk[16..20].copy_from_slice(&self.seq.to_le_bytes());
+        chunk[20..24].copy_from_slice(&self.nranks.to_le_bytes());
+        chunk[24..28].copy_from_slice(&self.rank.to_le_bytes());
+        chunk[28..32].copy_from_slice(&self.data_type.to_le_bytes());
+        chunk[32..36].copy_from_slice(&self.op.to_le_bytes());
+        chunk
+    }
+
+    /// Splits a received chunk into its header and payload.
+    pub fn decode(chunk: &[u8]) -> Result<(Self, &[u8])> {
+        if chunk.len() < Self::LEN {
+            log::warn!("chunk of {} bytes lacks a header", chunk.len());
+            return Err(Error::InvalidArgument);
+        }
+
+        let (header, payload) = chunk.split_at(Self::LEN);
+        let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let long = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+
+        Ok((
+            Self {
+                group: long(0),
+                offset: long(8),
+                seq: word(16),
+                nranks: word(20),
+                rank: word(24),
+                data_type: word(28),
+                op: word(32),
+            },
+            payload,
+        ))
+    }
+}
+
+/// What `listen` hands to `connect` through NCCL: where to send the handshake and to which
+/// listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle {
+    pub addr: SocketAddr,
+    pub listener: u32,
+}
+
+impl Handle {
+    pub fn encode(&self, handle: &mut [u8]) {
+        let encoded = CString::new(format!("{}/{}", self.addr, self.listener)).unwrap();
+        let encoded = encoded.as_bytes_with_nul();
+        handle[..encoded.len()].copy_from_slice(encoded);
+    }
+
+    pub fn decode(handle: &[u8]) -> Result<Self> {
+        let handle = CStr::from_bytes_until_nul(handle)
+            .ok()
+            .and_then(|handle| handle.to_str().ok())
+            .ok_or(Error::InvalidArgument)?;
+
+        let (addr, listener) = handle.split_once('/').ok_or(Error::InvalidArgument)?;
+
+        Ok(Self {
+            addr: addr.parse().map_err(|_| Error::InvalidArgument)?,
+            listener: listener.parse().map_err(|_| Error::InvalidArgument)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wire::*;
+
+    #[test]
+    fn header() {
+        let header = Header {
+            kind: Kind::Data,
+            tag: -1,
+            conn: 7,
+            seq: 42,
+            offset: 1 << 20,
+            length: 3 << 20,
+        };
+        let mut fragment = header.encode().to_vec();
+        fragment.extend_from_slice(b"payload");
+
+        assert_eq!(
+            Header::decode(&fragment).unwrap(),
+            (header, &b"payload"[..])
+        );
+        assert!(Header::decode(&fragment[..Header::LEN - 1]).is_err());
+
+        fragment[20] = 2;
+        assert!(Header::decode(&fragment).is_err());
+    }
+
+    #[test]
+    fn chunk() {
+        let chunk = Chunk {
+            group: u64::MAX - 1,
+            offset: 5 << 30,
+            seq: 3,
+            nranks: 4,
+            rank: 2,
+            data_type: 7,
+            op: 0,
+        };
+        let mut encoded = chunk.encode().to_vec();
+        encoded.extend_from_slice(b"payload");
+
+        assert_eq!(Chunk::decode(&encoded).unwrap(), (chunk, &b"payload"[..]));
+        assert!(Chunk::decode(&encoded[..Chunk::LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn handle() {
+        let handle = Handle {
+            addr: "[::1]:4000".parse().unwrap(),
+            listener: 7,
+        };
+        let mut encoded = [0u8; 128];
+        handle.encode(&mut encoded);
+
+        assert_eq!(Handle::decode(&encoded).unwrap(), handle);
+        assert!(Handle::decode(b"[::1]:4000\0").is_err());
+    }
+}
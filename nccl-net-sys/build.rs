@@ -5,14 +5,34 @@ use std::path::PathBuf;
 
 fn main() {
     println!("cargo:rerun-if-changed=wrapper.h");
+    println!("cargo:rerun-if-changed=include");
     let bindings = bindgen::Builder::default()
         .clang_arg("-Iinclude")
+        // ncclBfloat16 is only declared for CUDA compilers, its value is part of the ABI anyway.
+        .clang_arg("-D__CUDA_BF16_TYPES_EXIST__")
         .header("wrapper.h")
+        .whitelist_type("ncclNet_v4_t")
+        .whitelist_type("ncclNet_v5_t")
         .whitelist_type("ncclNet_v6_t")
+        .whitelist_type("ncclNet_v7_t")
+        .whitelist_type("ncclNet_v8_t")
+        .whitelist_type("ncclCollNet_v6_t")
+        .whitelist_type("ncclTuner_v1_t")
+        .whitelist_type("ncclTuner_v2_t")
         .whitelist_type("ncclDebugLogSubSys")
         .bitfield_enum("ncclDebugLogSubSys")
+        // NCCL passes values beyond the declared ones, e.g. ops created by ncclRedOpCreate*.
+        .newtype_enum("ncclDataType_t")
+        .newtype_enum("ncclRedOp_t")
+        // Collectives added by later versions of NCCL must not be undefined behavior.
+        .newtype_enum("ncclFunc_t")
         .whitelist_var("NCCL_PTR_.*")
         .whitelist_var("NCCL_NET_HANDLE_MAXSIZE")
+        .whitelist_var("NCCL_NET_MAX_REQUESTS")
+        .whitelist_var("NCCL_ALGO_.*")
+        .whitelist_var("NCCL_PROTO_.*")
+        .whitelist_var("NCCL_NUM_ALGORITHMS")
+        .whitelist_var("NCCL_NUM_PROTOCOLS")
         .default_enum_style(bindgen::EnumVariation::Rust {
             non_exhaustive: false,
         })
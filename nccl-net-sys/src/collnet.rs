@@ -0,0 +1,316 @@
+//! A safe interface for CollNet plugins, see [`CollNetPlugin`] and [`export_coll_net_plugin`].
+//!
+//! Like [`crate::plugin`] for the point-to-point interface, only v6 of the CollNet interface
+//! is exported.
+
+#![allow(clippy::missing_safety_doc)]
+
+use crate::plugin::{buffer, buffer_mut, guard, set_logger, size};
+use crate::*;
+use std::ffi::{c_char, c_int, c_void};
+use std::ptr::null_mut;
+use std::slice;
+
+/// A network offloading reductions, exported with [`export_coll_net_plugin`].
+///
+/// Mirrors `ncclCollNet_v6_t` of `nccl_net.h`, NCCL may call any function from any thread.
+pub trait CollNetPlugin: Sized {
+    type ListenComm;
+    type CollComm;
+    /// An outstanding reduction, may borrow its buffers and memory handles but not its comm,
+    /// like [`crate::plugin::NetPlugin::Request`].
+    type Request<'a>
+    where
+        Self: 'a;
+    type MemoryHandle;
+    type Error: Into<ncclResult_t>;
+
+    fn init(logger: ncclDebugLogger_t) -> Result<(), Self::Error>;
+
+    /// May be 0, NCCL then does not use the plugin.
+    fn devices() -> Result<i32, Self::Error>;
+
+    fn get_properties(dev: i32) -> Result<ncclNetProperties_v6_t, Self::Error>;
+
+    /// Writes what the other ranks need to join the group to `handle`, which is
+    /// `NCCL_NET_HANDLE_MAXSIZE` bytes.
+    fn listen(dev: i32, handle: &mut [u8]) -> Result<Self::ListenComm, Self::Error>;
+
+    /// Joins the group of every rank's `listen`, `handles` are theirs in rank order.
+    fn connect(
+        handles: &[&[u8]],
+        rank: usize,
+        listen_comm: &mut Self::ListenComm,
+    ) -> Result<Self::CollComm, Self::Error>;
+
+    fn reduce_support(data_type: ncclDataType_t, op: ncclRedOp_t) -> Result<bool, Self::Error>;
+
+    fn reg_mr(
+        comm: &mut Self::CollComm,
+        data: *mut c_void,
+        size: usize,
+        type_: i32,
+    ) -> Result<Self::MemoryHandle, Self::Error>;
+
+    /// The handle is only released if this succeeds.
+    fn dereg_mr(comm: &mut Self::CollComm, mhandle: &Self::MemoryHandle)
+        -> Result<(), Self::Error>;
+
+    /// Returns `None` if the reduction cannot be posted right now.
+    fn iallreduce<'a>(
+        comm: &mut Self::CollComm,
+        reduction: Reduction<'a, Self::MemoryHandle>,
+    ) -> Result<Option<Self::Request<'a>>, Self::Error>;
+
+    /// Returns the size of the completed request in bytes.
+    fn test(request: &mut Self::Request<'_>) -> Result<Option<usize>, Self::Error>;
+
+    fn close_coll(comm: Self::CollComm) -> Result<(), Self::Error>;
+
+    fn close_listen(listen_comm: Self::ListenComm) -> Result<(), Self::Error>;
+}
+
+/// The arguments of an `iallreduce`.
+pub struct Reduction<'a, M> {
+    /// `None` if the reduction is in place, `recv` holds the input then.
+    pub send: Option<&'a [u8]>,
+    pub recv: &'a mut [u8],
+    pub count: usize,
+    pub data_type: ncclDataType_t,
+    pub op: ncclRedOp_t,
+    pub send_mhandle: Option<&'a M>,
+    pub recv_mhandle: Option<&'a M>,
+}
+
+/// The size of an element of `data_type`, `None` for types NCCL does not define.
+pub fn data_type_size(data_type: ncclDataType_t) -> Option<usize> {
+    match data_type {
+        ncclDataType_t::ncclInt8 | ncclDataType_t::ncclUint8 => Some(1),
+        ncclDataType_t::ncclFloat16 | ncclDataType_t::ncclBfloat16 => Some(2),
+        ncclDataType_t::ncclInt32 | ncclDataType_t::ncclUint32 | ncclDataType_t::ncclFloat32 => {
+            Some(4)
+        }
+        ncclDataType_t::ncclInt64 | ncclDataType_t::ncclUint64 | ncclDataType_t::ncclFloat64 => {
+            Some(8)
+        }
+        _ => None,
+    }
+}
+
+/// Exports the table of the CollNet interface for a [`CollNetPlugin`].
+///
+/// ```ignore
+/// nccl_net_sys::export_coll_net_plugin!(HomaCollNet, "homa");
+/// ```
+#[macro_export]
+macro_rules! export_coll_net_plugin {
+    ($plugin:ty, $name:literal) => {
+        #[export_name = "ncclCollNetPlugin_v6"]
+        pub static mut NCCL_COLL_NET_PLUGIN_V6: $crate::ncclCollNet_v6_t =
+            $crate::collnet::coll_net_v6::<$plugin>(concat!($name, "\0").as_ptr().cast());
+    };
+}
+
+pub const fn coll_net_v6<P: CollNetPlugin>(name: *const c_char) -> ncclCollNet_v6_t {
+    ncclCollNet_v6_t {
+        name,
+        init: Some(init::<P>),
+        devices: Some(devices::<P>),
+        getProperties: Some(get_properties::<P>),
+        listen: Some(listen::<P>),
+        connect: Some(connect::<P>),
+        reduceSupport: Some(reduce_support::<P>),
+        regMr: Some(reg_mr::<P>),
+        regMrDmaBuf: None,
+        deregMr: Some(dereg_mr::<P>),
+        iallreduce: Some(iallreduce::<P>),
+        iflush: None,
+        test: Some(test::<P>),
+        closeColl: Some(close_coll::<P>),
+        closeListen: Some(close_listen::<P>),
+    }
+}
+
+pub unsafe extern "C" fn init<P: CollNetPlugin>(logger: ncclDebugLogger_t) -> ncclResult_t {
+    guard(|| {
+        set_logger(logger);
+        P::init(logger).map_err(Into::into)
+    })
+}
+
+pub unsafe extern "C" fn devices<P: CollNetPlugin>(ndev: *mut c_int) -> ncclResult_t {
+    guard(|| {
+        *ndev = P::devices().map_err(Into::into)?;
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn get_properties<P: CollNetPlugin>(
+    dev: c_int,
+    props: *mut ncclNetProperties_v6_t,
+) -> ncclResult_t {
+    guard(|| {
+        *props = P::get_properties(dev).map_err(Into::into)?;
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn listen<P: CollNetPlugin>(
+    dev: c_int,
+    handle: *mut c_void,
+    listen_comm: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let handle = slice::from_raw_parts_mut(handle.cast(), NCCL_NET_HANDLE_MAXSIZE as usize);
+        let comm = P::listen(dev, handle).map_err(Into::into)?;
+        *listen_comm = Box::into_raw(Box::new(comm)).cast();
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn connect<P: CollNetPlugin>(
+    handles: *mut *mut c_void,
+    nranks: c_int,
+    rank: c_int,
+    listen_comm: *mut c_void,
+    coll_comm: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let handles: Vec<&[u8]> = slice::from_raw_parts(handles, size(nranks)?)
+            .iter()
+            .map(|handle| slice::from_raw_parts(handle.cast(), NCCL_NET_HANDLE_MAXSIZE as usize))
+            .collect();
+        let rank = size(rank)?;
+        if rank >= handles.len() {
+            return Err(ncclResult_t::ncclInvalidArgument);
+        }
+        let listen_comm = &mut *listen_comm.cast::<P::ListenComm>();
+        let comm = P::connect(&handles, rank, listen_comm).map_err(Into::into)?;
+        *coll_comm = Box::into_raw(Box::new(comm)).cast();
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn reduce_support<P: CollNetPlugin>(
+    data_type: ncclDataType_t,
+    op: ncclRedOp_t,
+    supported: *mut c_int,
+) -> ncclResult_t {
+    guard(|| {
+        *supported = P::reduce_support(data_type, op).map_err(Into::into)? as c_int;
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn reg_mr<P: CollNetPlugin>(
+    comm: *mut c_void,
+    data: *mut c_void,
+    size: c_int,
+    type_: c_int,
+    mhandle: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let comm = &mut *comm.cast::<P::CollComm>();
+        let handle = P::reg_mr(comm, data, self::size(size)?, type_).map_err(Into::into)?;
+        *mhandle = Box::into_raw(Box::new(handle)).cast();
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn dereg_mr<P: CollNetPlugin>(
+    comm: *mut c_void,
+    mhandle: *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let comm = &mut *comm.cast::<P::CollComm>();
+        let mhandle = mhandle.cast::<P::MemoryHandle>();
+        P::dereg_mr(comm, &*mhandle).map_err(Into::into)?;
+        drop(Box::from_raw(mhandle));
+        Ok(())
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn iallreduce<P: CollNetPlugin>(
+    comm: *mut c_void,
+    send_data: *mut c_void,
+    recv_data: *mut c_void,
+    count: c_int,
+    data_type: ncclDataType_t,
+    op: ncclRedOp_t,
+    send_mhandle: *mut c_void,
+    recv_mhandle: *mut c_void,
+    request: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let comm = &mut *comm.cast::<P::CollComm>();
+        let count = size(count)?;
+        let length = data_type_size(data_type)
+            .and_then(|size| size.checked_mul(count))
+            .ok_or(ncclResult_t::ncclInvalidArgument)?;
+
+        // The input may only be read before the result is written, the buffers must not
+        // overlap unless the reduction is in place.
+        let send = if send_data == recv_data {
+            None
+        } else {
+            let (send, recv) = (send_data as usize, recv_data as usize);
+            if send < recv + length && recv < send + length {
+                return Err(ncclResult_t::ncclInvalidArgument);
+            }
+            Some(buffer(send_data, length))
+        };
+
+        let reduction = Reduction {
+            send,
+            recv: buffer_mut(recv_data, length),
+            count,
+            data_type,
+            op,
+            send_mhandle: send_mhandle.cast::<P::MemoryHandle>().as_ref(),
+            recv_mhandle: recv_mhandle.cast::<P::MemoryHandle>().as_ref(),
+        };
+        *request = match P::iallreduce(comm, reduction).map_err(Into::into)? {
+            Some(req) => Box::into_raw(Box::new(req)).cast(),
+            None => null_mut(),
+        };
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn test<P: CollNetPlugin>(
+    request: *mut c_void,
+    done: *mut c_int,
+    size: *mut c_int,
+) -> ncclResult_t {
+    guard(|| {
+        let request = request.cast::<P::Request<'_>>();
+        match P::test(&mut *request).map_err(Into::into)? {
+            Some(length) => {
+                *done = 1;
+                if !size.is_null() {
+                    *size = length
+                        .try_into()
+                        .map_err(|_| ncclResult_t::ncclInternalError)?;
+                }
+                drop(Box::from_raw(request));
+            }
+            None => *done = 0,
+        }
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn close_coll<P: CollNetPlugin>(comm: *mut c_void) -> ncclResult_t {
+    guard(|| {
+        let comm = Box::from_raw(comm.cast::<P::CollComm>());
+        P::close_coll(*comm).map_err(Into::into)
+    })
+}
+
+pub unsafe extern "C" fn close_listen<P: CollNetPlugin>(listen_comm: *mut c_void) -> ncclResult_t {
+    guard(|| {
+        let listen_comm = Box::from_raw(listen_comm.cast::<P::ListenComm>());
+        P::close_listen(*listen_comm).map_err(Into::into)
+    })
+}
@@ -4,3 +4,7 @@
 #![allow(deref_nullptr)]
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+pub mod collnet;
+pub mod plugin;
+pub mod tuner;
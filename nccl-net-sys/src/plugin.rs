@@ -0,0 +1,676 @@
+//! A safe interface for net plugins, see [`NetPlugin`] and [`export_net_plugin`].
+//!
+//! The entry points here take care of the pointers NCCL hands out and back in: comms,
+//! requests and memory handles are boxed, sizes are checked, panics are caught and logged
+//! through NCCL. Older and newer versions of the interface are adapted onto the same trait.
+//!
+//! The `extern "C"` functions are public for tables built by hand, they expect the pointers
+//! NCCL passes as documented in `nccl_net.h`.
+
+#![allow(clippy::missing_safety_doc)]
+
+use crate::*;
+use std::any::Any;
+use std::ffi::{c_char, c_int, c_ulong, c_void, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::null_mut;
+use std::sync::{Mutex, PoisonError};
+use std::{slice, thread};
+
+/// The logger passed to `init`, for reporting panics.
+static LOGGER: Mutex<ncclDebugLogger_t> = Mutex::new(None);
+
+/// A transport NCCL can run over, exported with [`export_net_plugin`].
+///
+/// Mirrors the v6 interface of `nccl_net.h`, NCCL may call any function from any thread.
+pub trait NetPlugin: Sized {
+    type ListenComm;
+    type SendComm;
+    type RecvComm;
+    /// An outstanding send or receive, may borrow its buffers and memory handles. NCCL keeps
+    /// posting on and may close the comm while requests are outstanding, they must not
+    /// borrow it.
+    type Request<'a>
+    where
+        Self: 'a;
+    type MemoryHandle;
+    type Error: Into<ncclResult_t>;
+
+    /// Whether a registration is usable with every comm and registering the same memory
+    /// again is cheap, reported to NCCL from v8 on so it may keep registrations across comms.
+    const REG_IS_GLOBAL: bool = false;
+
+    fn init(logger: ncclDebugLogger_t) -> Result<(), Self::Error>;
+
+    fn devices() -> Result<i32, Self::Error>;
+
+    fn get_properties(dev: i32) -> Result<ncclNetProperties_v6_t, Self::Error>;
+
+    /// Writes what `connect` needs to reach the listener to `handle`, which is
+    /// `NCCL_NET_HANDLE_MAXSIZE` bytes.
+    fn listen(dev: i32, handle: &mut [u8]) -> Result<Self::ListenComm, Self::Error>;
+
+    /// Must not block, returns `None` until the connection is established and is then called
+    /// again with the same handle.
+    fn connect(dev: i32, handle: &mut [u8]) -> Result<Option<Self::SendComm>, Self::Error>;
+
+    /// Must not block, returns `None` until a peer connected.
+    fn accept(listen_comm: &mut Self::ListenComm) -> Result<Option<Self::RecvComm>, Self::Error>;
+
+    /// Registers `size` bytes at `data`, which is device memory unless `type_` is
+    /// `NCCL_PTR_HOST`.
+    fn reg_mr(
+        comm: &mut Comm<Self>,
+        data: *mut c_void,
+        size: usize,
+        type_: i32,
+    ) -> Result<Self::MemoryHandle, Self::Error>;
+
+    /// The handle is only released if this succeeds.
+    fn dereg_mr(comm: &mut Comm<Self>, mhandle: &Self::MemoryHandle) -> Result<(), Self::Error>;
+
+    /// Returns `None` if the send cannot be posted right now.
+    fn isend<'a>(
+        send_comm: &mut Self::SendComm,
+        data: &'a [u8],
+        tag: i32,
+        mhandle: Option<&'a Self::MemoryHandle>,
+    ) -> Result<Option<Self::Request<'a>>, Self::Error>;
+
+    /// Posts a grouped receive of one message per buffer, returns `None` if it cannot be
+    /// posted right now.
+    fn irecv<'a>(
+        recv_comm: &mut Self::RecvComm,
+        buffers: Vec<Buffer<'a, Self::MemoryHandle>>,
+    ) -> Result<Option<Self::Request<'a>>, Self::Error>;
+
+    /// Returns the sizes of the completed request, one per buffer of a receive.
+    fn test(request: &mut Self::Request<'_>) -> Result<Option<Vec<i32>>, Self::Error>;
+
+    fn close_send(send_comm: Self::SendComm) -> Result<(), Self::Error>;
+
+    fn close_recv(recv_comm: Self::RecvComm) -> Result<(), Self::Error>;
+
+    fn close_listen(listen_comm: Self::ListenComm) -> Result<(), Self::Error>;
+}
+
+/// A send or receive comm, memory is registered with either.
+pub enum Comm<P: NetPlugin> {
+    Send(P::SendComm),
+    Recv(P::RecvComm),
+}
+
+/// A buffer of a grouped receive.
+pub struct Buffer<'a, M> {
+    pub data: &'a mut [u8],
+    pub tag: i32,
+    pub mhandle: Option<&'a M>,
+}
+
+/// Exports the tables of every supported interface version for a [`NetPlugin`].
+///
+/// ```ignore
+/// nccl_net_sys::export_net_plugin!(Homa, "homa");
+/// ```
+#[macro_export]
+macro_rules! export_net_plugin {
+    ($plugin:ty, $name:literal) => {
+        #[export_name = "ncclNetPlugin_v8"]
+        pub static mut NCCL_NET_PLUGIN_V8: $crate::ncclNet_v8_t =
+            $crate::plugin::net_v8::<$plugin>(concat!($name, "\0").as_ptr().cast());
+        #[export_name = "ncclNetPlugin_v7"]
+        pub static mut NCCL_NET_PLUGIN_V7: $crate::ncclNet_v7_t =
+            $crate::plugin::net_v7::<$plugin>(concat!($name, "\0").as_ptr().cast());
+        #[export_name = "ncclNetPlugin_v6"]
+        pub static mut NCCL_NET_PLUGIN_V6: $crate::ncclNet_v6_t =
+            $crate::plugin::net_v6::<$plugin>(concat!($name, "\0").as_ptr().cast());
+        #[export_name = "ncclNetPlugin_v5"]
+        pub static mut NCCL_NET_PLUGIN_V5: $crate::ncclNet_v5_t =
+            $crate::plugin::net_v5::<$plugin>(concat!($name, "\0").as_ptr().cast());
+        #[export_name = "ncclNetPlugin_v4"]
+        pub static mut NCCL_NET_PLUGIN_V4: $crate::ncclNet_v4_t =
+            $crate::plugin::net_v4::<$plugin>(concat!($name, "\0").as_ptr().cast());
+    };
+}
+
+pub const fn net_v8<P: NetPlugin>(name: *const c_char) -> ncclNet_v8_t {
+    ncclNet_v8_t {
+        name,
+        init: Some(init::<P>),
+        devices: Some(devices::<P>),
+        getProperties: Some(get_properties_v8::<P>),
+        listen: Some(listen::<P>),
+        connect: Some(connect_v7::<P>),
+        accept: Some(accept_v7::<P>),
+        regMr: Some(reg_mr_v8::<P>),
+        regMrDmaBuf: None,
+        deregMr: Some(dereg_mr::<P>),
+        isend: Some(isend::<P>),
+        irecv: Some(irecv::<P>),
+        iflush: None,
+        test: Some(test::<P>),
+        closeSend: Some(close_send::<P>),
+        closeRecv: Some(close_recv::<P>),
+        closeListen: Some(close_listen::<P>),
+        getDeviceMr: None,
+        irecvConsumed: None,
+    }
+}
+
+pub const fn net_v7<P: NetPlugin>(name: *const c_char) -> ncclNet_v7_t {
+    ncclNet_v7_t {
+        name,
+        init: Some(init::<P>),
+        devices: Some(devices::<P>),
+        getProperties: Some(get_properties_v7::<P>),
+        listen: Some(listen::<P>),
+        connect: Some(connect_v7::<P>),
+        accept: Some(accept_v7::<P>),
+        regMr: Some(reg_mr::<P>),
+        regMrDmaBuf: None,
+        deregMr: Some(dereg_mr::<P>),
+        isend: Some(isend::<P>),
+        irecv: Some(irecv::<P>),
+        iflush: None,
+        test: Some(test::<P>),
+        closeSend: Some(close_send::<P>),
+        closeRecv: Some(close_recv::<P>),
+        closeListen: Some(close_listen::<P>),
+        getDeviceMr: None,
+        irecvConsumed: None,
+    }
+}
+
+pub const fn net_v6<P: NetPlugin>(name: *const c_char) -> ncclNet_v6_t {
+    ncclNet_v6_t {
+        name,
+        init: Some(init::<P>),
+        devices: Some(devices::<P>),
+        getProperties: Some(get_properties::<P>),
+        listen: Some(listen::<P>),
+        connect: Some(connect::<P>),
+        accept: Some(accept::<P>),
+        regMr: Some(reg_mr::<P>),
+        regMrDmaBuf: None,
+        deregMr: Some(dereg_mr::<P>),
+        isend: Some(isend::<P>),
+        irecv: Some(irecv::<P>),
+        iflush: None,
+        test: Some(test::<P>),
+        closeSend: Some(close_send::<P>),
+        closeRecv: Some(close_recv::<P>),
+        closeListen: Some(close_listen::<P>),
+    }
+}
+
+pub const fn net_v5<P: NetPlugin>(name: *const c_char) -> ncclNet_v5_t {
+    ncclNet_v5_t {
+        name,
+        init: Some(init::<P>),
+        devices: Some(devices::<P>),
+        getProperties: Some(get_properties::<P>),
+        listen: Some(listen::<P>),
+        connect: Some(connect::<P>),
+        accept: Some(accept::<P>),
+        regMr: Some(reg_mr::<P>),
+        deregMr: Some(dereg_mr::<P>),
+        isend: Some(isend::<P>),
+        irecv: Some(irecv::<P>),
+        iflush: None,
+        test: Some(test::<P>),
+        closeSend: Some(close_send::<P>),
+        closeRecv: Some(close_recv::<P>),
+        closeListen: Some(close_listen::<P>),
+    }
+}
+
+pub const fn net_v4<P: NetPlugin>(name: *const c_char) -> ncclNet_v4_t {
+    ncclNet_v4_t {
+        name,
+        init: Some(init::<P>),
+        devices: Some(devices::<P>),
+        getProperties: Some(get_properties_v4::<P>),
+        listen: Some(listen::<P>),
+        connect: Some(connect_v4::<P>),
+        accept: Some(accept_v4::<P>),
+        regMr: Some(reg_mr::<P>),
+        deregMr: Some(dereg_mr::<P>),
+        isend: Some(isend_v4::<P>),
+        irecv: Some(irecv_v4::<P>),
+        iflush: None,
+        test: Some(test::<P>),
+        closeSend: Some(close_send::<P>),
+        closeRecv: Some(close_recv::<P>),
+        closeListen: Some(close_listen::<P>),
+    }
+}
+
+/// Runs the body of an entry point, turning errors and panics into a result for NCCL, a
+/// panic unwinding into NCCL would abort the whole job.
+pub fn guard(f: impl FnOnce() -> Result<(), ncclResult_t>) -> ncclResult_t {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => ncclResult_t::ncclSuccess,
+        Ok(Err(err)) => err,
+        Err(payload) => {
+            warn(&format!("panic: {}", message(&*payload)));
+            ncclResult_t::ncclInternalError
+        }
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown", String::as_str),
+    }
+}
+
+pub(crate) fn set_logger(logger: ncclDebugLogger_t) {
+    *LOGGER.lock().unwrap_or_else(PoisonError::into_inner) = logger;
+}
+
+fn warn(message: &str) {
+    let logger = *LOGGER.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if let Some(logger) = logger {
+        let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+        unsafe {
+            logger(
+                ncclDebugLogLevel::NCCL_LOG_WARN,
+                ncclDebugLogSubSys::NCCL_NET.0 as c_ulong,
+                concat!(file!(), "\0").as_ptr().cast(),
+                line!() as c_int,
+                "%s\0".as_ptr().cast(),
+                message.as_ptr(),
+            );
+        }
+    }
+}
+
+/// Converts a size or count passed by NCCL, which must not be negative.
+pub(crate) fn size(size: c_int) -> Result<usize, ncclResult_t> {
+    size.try_into()
+        .map_err(|_| ncclResult_t::ncclInvalidArgument)
+}
+
+/// NCCL may pass null for empty buffers, which slices must not point to.
+pub(crate) unsafe fn buffer<'a>(data: *mut c_void, size: usize) -> &'a [u8] {
+    if size == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data.cast(), size)
+    }
+}
+
+pub(crate) unsafe fn buffer_mut<'a>(data: *mut c_void, size: usize) -> &'a mut [u8] {
+    if size == 0 {
+        &mut []
+    } else {
+        slice::from_raw_parts_mut(data.cast(), size)
+    }
+}
+
+pub unsafe extern "C" fn init<P: NetPlugin>(logger: ncclDebugLogger_t) -> ncclResult_t {
+    guard(|| {
+        set_logger(logger);
+        P::init(logger).map_err(Into::into)
+    })
+}
+
+pub unsafe extern "C" fn devices<P: NetPlugin>(ndev: *mut c_int) -> ncclResult_t {
+    guard(|| {
+        *ndev = P::devices().map_err(Into::into)?;
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn get_properties<P: NetPlugin>(
+    dev: c_int,
+    props: *mut ncclNetProperties_v6_t,
+) -> ncclResult_t {
+    guard(|| {
+        *props = P::get_properties(dev).map_err(Into::into)?;
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn listen<P: NetPlugin>(
+    dev: c_int,
+    handle: *mut c_void,
+    listen_comm: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let handle = slice::from_raw_parts_mut(handle.cast(), NCCL_NET_HANDLE_MAXSIZE as usize);
+        let comm = P::listen(dev, handle).map_err(Into::into)?;
+        *listen_comm = Box::into_raw(Box::new(comm)).cast();
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn connect<P: NetPlugin>(
+    dev: c_int,
+    handle: *mut c_void,
+    send_comm: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let handle = slice::from_raw_parts_mut(handle.cast(), NCCL_NET_HANDLE_MAXSIZE as usize);
+        *send_comm = match P::connect(dev, handle).map_err(Into::into)? {
+            Some(comm) => Box::into_raw(Box::new(Comm::<P>::Send(comm))).cast(),
+            None => null_mut(),
+        };
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn accept<P: NetPlugin>(
+    listen_comm: *mut c_void,
+    recv_comm: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let listen_comm = &mut *listen_comm.cast::<P::ListenComm>();
+        *recv_comm = match P::accept(listen_comm).map_err(Into::into)? {
+            Some(comm) => Box::into_raw(Box::new(Comm::<P>::Recv(comm))).cast(),
+            None => null_mut(),
+        };
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn reg_mr<P: NetPlugin>(
+    comm: *mut c_void,
+    data: *mut c_void,
+    size: c_int,
+    type_: c_int,
+    mhandle: *mut *mut c_void,
+) -> ncclResult_t {
+    match self::size(size) {
+        Ok(size) => reg_mr_v8::<P>(comm, data, size, type_, mhandle),
+        Err(err) => err,
+    }
+}
+
+pub unsafe extern "C" fn dereg_mr<P: NetPlugin>(
+    comm: *mut c_void,
+    mhandle: *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let comm = &mut *comm.cast::<Comm<P>>();
+        let mhandle = mhandle.cast::<P::MemoryHandle>();
+        P::dereg_mr(comm, &*mhandle).map_err(Into::into)?;
+        drop(Box::from_raw(mhandle));
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn isend<P: NetPlugin>(
+    send_comm: *mut c_void,
+    data: *mut c_void,
+    size: c_int,
+    tag: c_int,
+    mhandle: *mut c_void,
+    request: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let Comm::Send(send_comm) = &mut *send_comm.cast::<Comm<P>>() else {
+            return Err(ncclResult_t::ncclInvalidUsage);
+        };
+        let data = buffer(data, self::size(size)?);
+        let mhandle = mhandle.cast::<P::MemoryHandle>().as_ref();
+        *request = match P::isend(send_comm, data, tag, mhandle).map_err(Into::into)? {
+            Some(req) => Box::into_raw(Box::new(req)).cast(),
+            None => null_mut(),
+        };
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn irecv<P: NetPlugin>(
+    recv_comm: *mut c_void,
+    n: c_int,
+    data: *mut *mut c_void,
+    sizes: *mut c_int,
+    tags: *mut c_int,
+    mhandles: *mut *mut c_void,
+    request: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let Comm::Recv(recv_comm) = &mut *recv_comm.cast::<Comm<P>>() else {
+            return Err(ncclResult_t::ncclInvalidUsage);
+        };
+        let n = size(n)?;
+        let data = slice::from_raw_parts(data, n);
+        let sizes = slice::from_raw_parts(sizes, n);
+        let tags = slice::from_raw_parts(tags, n);
+        let mhandles = if mhandles.is_null() {
+            &[][..]
+        } else {
+            slice::from_raw_parts(mhandles, n)
+        };
+        let buffers = (0..n)
+            .map(|i| {
+                Ok(Buffer {
+                    data: buffer_mut(data[i], size(sizes[i])?),
+                    tag: tags[i],
+                    mhandle: mhandles
+                        .get(i)
+                        .and_then(|mhandle| mhandle.cast::<P::MemoryHandle>().as_ref()),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        *request = match P::irecv(recv_comm, buffers).map_err(Into::into)? {
+            Some(req) => Box::into_raw(Box::new(req)).cast(),
+            None => null_mut(),
+        };
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn test<P: NetPlugin>(
+    request: *mut c_void,
+    done: *mut c_int,
+    sizes: *mut c_int,
+) -> ncclResult_t {
+    guard(|| {
+        let request = request.cast::<P::Request<'_>>();
+        match P::test(&mut *request).map_err(Into::into)? {
+            Some(size) => {
+                *done = 1;
+                if !sizes.is_null() {
+                    slice::from_raw_parts_mut(sizes, size.len()).copy_from_slice(&size);
+                }
+                drop(Box::from_raw(request));
+            }
+            None => *done = 0,
+        }
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn close_send<P: NetPlugin>(send_comm: *mut c_void) -> ncclResult_t {
+    guard(|| match *Box::from_raw(send_comm.cast::<Comm<P>>()) {
+        Comm::Send(send_comm) => P::close_send(send_comm).map_err(Into::into),
+        Comm::Recv(_) => Err(ncclResult_t::ncclInvalidUsage),
+    })
+}
+
+pub unsafe extern "C" fn close_recv<P: NetPlugin>(recv_comm: *mut c_void) -> ncclResult_t {
+    guard(|| match *Box::from_raw(recv_comm.cast::<Comm<P>>()) {
+        Comm::Recv(recv_comm) => P::close_recv(recv_comm).map_err(Into::into),
+        Comm::Send(_) => Err(ncclResult_t::ncclInvalidUsage),
+    })
+}
+
+pub unsafe extern "C" fn close_listen<P: NetPlugin>(listen_comm: *mut c_void) -> ncclResult_t {
+    guard(|| {
+        let listen_comm = Box::from_raw(listen_comm.cast::<P::ListenComm>());
+        P::close_listen(*listen_comm).map_err(Into::into)
+    })
+}
+
+pub unsafe extern "C" fn get_properties_v4<P: NetPlugin>(
+    dev: c_int,
+    props: *mut ncclNetProperties_v4_t,
+) -> ncclResult_t {
+    guard(|| {
+        let p = P::get_properties(dev).map_err(Into::into)?;
+        *props = ncclNetProperties_v4_t {
+            name: p.name,
+            pciPath: p.pciPath,
+            guid: p.guid,
+            ptrSupport: p.ptrSupport,
+            speed: p.speed,
+            port: p.port,
+            maxComms: p.maxComms,
+        };
+        Ok(())
+    })
+}
+
+/// v4 expects connect to block until the connection is established.
+pub unsafe extern "C" fn connect_v4<P: NetPlugin>(
+    dev: c_int,
+    handle: *mut c_void,
+    send_comm: *mut *mut c_void,
+) -> ncclResult_t {
+    loop {
+        let ret = connect::<P>(dev, handle, send_comm);
+        if ret != ncclResult_t::ncclSuccess || !(*send_comm).is_null() {
+            return ret;
+        }
+        thread::yield_now();
+    }
+}
+
+/// v4 expects accept to block until a peer connected.
+pub unsafe extern "C" fn accept_v4<P: NetPlugin>(
+    listen_comm: *mut c_void,
+    recv_comm: *mut *mut c_void,
+) -> ncclResult_t {
+    loop {
+        let ret = accept::<P>(listen_comm, recv_comm);
+        if ret != ncclResult_t::ncclSuccess || !(*recv_comm).is_null() {
+            return ret;
+        }
+        thread::yield_now();
+    }
+}
+
+/// v4 has no tags, all messages of a connection are matched in order.
+pub unsafe extern "C" fn isend_v4<P: NetPlugin>(
+    send_comm: *mut c_void,
+    data: *mut c_void,
+    size: c_int,
+    mhandle: *mut c_void,
+    request: *mut *mut c_void,
+) -> ncclResult_t {
+    isend::<P>(send_comm, data, size, 0, mhandle, request)
+}
+
+/// v4 posts a single receive at a time.
+pub unsafe extern "C" fn irecv_v4<P: NetPlugin>(
+    recv_comm: *mut c_void,
+    data: *mut c_void,
+    size: c_int,
+    mhandle: *mut c_void,
+    request: *mut *mut c_void,
+) -> ncclResult_t {
+    let (mut data, mut size, mut tag, mut mhandle) = (data, size, 0, mhandle);
+    irecv::<P>(
+        recv_comm,
+        1,
+        &mut data,
+        &mut size,
+        &mut tag,
+        &mut mhandle,
+        request,
+    )
+}
+
+pub unsafe extern "C" fn get_properties_v7<P: NetPlugin>(
+    dev: c_int,
+    props: *mut ncclNetProperties_v7_t,
+) -> ncclResult_t {
+    guard(|| {
+        let p = P::get_properties(dev).map_err(Into::into)?;
+        *props = ncclNetProperties_v7_t {
+            name: p.name,
+            pciPath: p.pciPath,
+            guid: p.guid,
+            ptrSupport: p.ptrSupport,
+            speed: p.speed,
+            port: p.port,
+            latency: p.latency,
+            maxComms: p.maxComms,
+            maxRecvs: p.maxRecvs,
+            netDeviceType: ncclNetDeviceType::NCCL_NET_DEVICE_HOST,
+            netDeviceVersion: 0,
+        };
+        Ok(())
+    })
+}
+
+/// Device offload is never requested, the device type reported is the host and no device
+/// handle is handed out. The v8 device handle is the same type.
+pub unsafe extern "C" fn connect_v7<P: NetPlugin>(
+    dev: c_int,
+    handle: *mut c_void,
+    send_comm: *mut *mut c_void,
+    send_dev_comm: *mut *mut ncclNetDeviceHandle_v7_t,
+) -> ncclResult_t {
+    if !send_dev_comm.is_null() {
+        *send_dev_comm = null_mut();
+    }
+    connect::<P>(dev, handle, send_comm)
+}
+
+pub unsafe extern "C" fn accept_v7<P: NetPlugin>(
+    listen_comm: *mut c_void,
+    recv_comm: *mut *mut c_void,
+    recv_dev_comm: *mut *mut ncclNetDeviceHandle_v7_t,
+) -> ncclResult_t {
+    if !recv_dev_comm.is_null() {
+        *recv_dev_comm = null_mut();
+    }
+    accept::<P>(listen_comm, recv_comm)
+}
+
+pub unsafe extern "C" fn get_properties_v8<P: NetPlugin>(
+    dev: c_int,
+    props: *mut ncclNetProperties_v8_t,
+) -> ncclResult_t {
+    guard(|| {
+        let p = P::get_properties(dev).map_err(Into::into)?;
+        *props = ncclNetProperties_v8_t {
+            name: p.name,
+            pciPath: p.pciPath,
+            guid: p.guid,
+            ptrSupport: p.ptrSupport,
+            regIsGlobal: P::REG_IS_GLOBAL.into(),
+            speed: p.speed,
+            port: p.port,
+            latency: p.latency,
+            maxComms: p.maxComms,
+            maxRecvs: p.maxRecvs,
+            netDeviceType: ncclNetDeviceType::NCCL_NET_DEVICE_HOST,
+            netDeviceVersion: 0,
+        };
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn reg_mr_v8<P: NetPlugin>(
+    comm: *mut c_void,
+    data: *mut c_void,
+    size: usize,
+    type_: c_int,
+    mhandle: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        let comm = &mut *comm.cast::<Comm<P>>();
+        let handle = P::reg_mr(comm, data, size, type_).map_err(Into::into)?;
+        *mhandle = Box::into_raw(Box::new(handle)).cast();
+        Ok(())
+    })
+}
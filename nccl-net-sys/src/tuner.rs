@@ -0,0 +1,206 @@
+//! A safe interface for tuner plugins, see [`TunerPlugin`] and [`export_tuner_plugin`].
+//!
+//! Both v1 and v2 of the tuner interface are exported. v1 has no context, a process tunes
+//! with the context of the comm initialized last.
+
+#![allow(clippy::missing_safety_doc)]
+
+use crate::plugin::{guard, set_logger};
+use crate::*;
+use std::ffi::{c_char, c_int, c_void};
+use std::ptr::null_mut;
+use std::sync::{PoisonError, RwLock};
+
+/// The context of v1, a `Box<P::Context>` or null.
+static CONTEXT_V1: RwLock<usize> = RwLock::new(0);
+
+/// Selects how NCCL runs collectives, exported with [`export_tuner_plugin`].
+///
+/// Mirrors `ncclTuner_v2_t` of `nccl_tuner.h`, every comm initializes a context of its own.
+pub trait TunerPlugin: Sized {
+    type Context: Send + Sync;
+    type Error: Into<ncclResult_t>;
+
+    fn init(
+        nranks: usize,
+        nnodes: usize,
+        logger: ncclDebugLogger_t,
+    ) -> Result<Self::Context, Self::Error>;
+
+    /// An error also leaves the choice to NCCL.
+    fn get_coll_info(
+        context: &Self::Context,
+        collective: &Collective,
+    ) -> Result<Selection, Self::Error>;
+
+    fn destroy(context: Self::Context) -> Result<(), Self::Error>;
+}
+
+/// The collective NCCL asks a [`Selection`] for.
+#[derive(Debug, Clone, Copy)]
+pub struct Collective {
+    pub func: ncclFunc_t,
+    pub bytes: usize,
+    pub collnet_support: bool,
+    pub nvls_support: bool,
+    /// The number of operations in the group.
+    pub pipe_ops: i32,
+}
+
+/// What is left `None` is chosen by NCCL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Selection {
+    /// One of `NCCL_ALGO_*` and one of `NCCL_PROTO_*`, NCCL takes both or neither.
+    pub algorithm: Option<(c_int, c_int)>,
+    pub channels: Option<c_int>,
+}
+
+/// Exports the tables of the tuner interface for a [`TunerPlugin`].
+///
+/// ```ignore
+/// nccl_net_sys::export_tuner_plugin!(HomaTuner, "homa");
+/// ```
+#[macro_export]
+macro_rules! export_tuner_plugin {
+    ($plugin:ty, $name:literal) => {
+        #[export_name = "ncclTunerPlugin_v2"]
+        pub static mut NCCL_TUNER_PLUGIN_V2: $crate::ncclTuner_v2_t =
+            $crate::tuner::tuner_v2::<$plugin>(concat!($name, "\0").as_ptr().cast());
+
+        #[export_name = "ncclTunerPlugin_v1"]
+        pub static mut NCCL_TUNER_PLUGIN_V1: $crate::ncclTuner_v1_t =
+            $crate::tuner::tuner_v1::<$plugin>(concat!($name, "\0").as_ptr().cast());
+    };
+}
+
+pub const fn tuner_v2<P: TunerPlugin>(name: *const c_char) -> ncclTuner_v2_t {
+    ncclTuner_v2_t {
+        name,
+        init: Some(init::<P>),
+        getCollInfo: Some(get_coll_info::<P>),
+        destroy: Some(destroy::<P>),
+    }
+}
+
+pub const fn tuner_v1<P: TunerPlugin>(name: *const c_char) -> ncclTuner_v1_t {
+    ncclTuner_v1_t {
+        name,
+        init: Some(init_v1::<P>),
+        getCollInfo: Some(get_coll_info_v1::<P>),
+        destroy: Some(destroy_v1::<P>),
+    }
+}
+
+pub unsafe extern "C" fn init<P: TunerPlugin>(
+    nranks: usize,
+    nnodes: usize,
+    logger: ncclDebugLogger_t,
+    context: *mut *mut c_void,
+) -> ncclResult_t {
+    guard(|| {
+        set_logger(logger);
+        let ctx = P::init(nranks, nnodes, logger).map_err(Into::into)?;
+        *context = Box::into_raw(Box::new(ctx)).cast();
+        Ok(())
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn get_coll_info<P: TunerPlugin>(
+    context: *mut c_void,
+    func: ncclFunc_t,
+    bytes: usize,
+    collnet_support: c_int,
+    nvls_support: c_int,
+    pipe_ops: c_int,
+    algorithm: *mut c_int,
+    protocol: *mut c_int,
+    channels: *mut c_int,
+) -> ncclResult_t {
+    guard(|| {
+        let context = &*context.cast::<P::Context>();
+        let collective = Collective {
+            func,
+            bytes,
+            collnet_support: collnet_support != 0,
+            nvls_support: nvls_support != 0,
+            pipe_ops,
+        };
+        let selection = P::get_coll_info(context, &collective).map_err(Into::into)?;
+
+        if let Some((algo, proto)) = selection.algorithm {
+            *algorithm = algo;
+            *protocol = proto;
+        }
+        if let Some(n) = selection.channels {
+            *channels = n;
+        }
+        Ok(())
+    })
+}
+
+pub unsafe extern "C" fn destroy<P: TunerPlugin>(context: *mut c_void) -> ncclResult_t {
+    guard(|| {
+        let context = Box::from_raw(context.cast::<P::Context>());
+        P::destroy(*context).map_err(Into::into)
+    })
+}
+
+pub unsafe extern "C" fn init_v1<P: TunerPlugin>(
+    nranks: usize,
+    nnodes: usize,
+    logger: ncclDebugLogger_t,
+) -> ncclResult_t {
+    guard(|| {
+        let mut context = null_mut();
+        match init::<P>(nranks, nnodes, logger, &mut context) {
+            ncclResult_t::ncclSuccess => {}
+            err => return Err(err),
+        }
+
+        let mut current = CONTEXT_V1.write().unwrap_or_else(PoisonError::into_inner);
+        let previous = std::mem::replace(&mut *current, context as usize);
+        if previous != 0 {
+            destroy::<P>(previous as *mut c_void);
+        }
+        Ok(())
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn get_coll_info_v1<P: TunerPlugin>(
+    func: ncclFunc_t,
+    bytes: usize,
+    collnet_support: c_int,
+    nvls_support: c_int,
+    pipe_ops: c_int,
+    algorithm: *mut c_int,
+    protocol: *mut c_int,
+    channels: *mut c_int,
+) -> ncclResult_t {
+    let context = CONTEXT_V1.read().unwrap_or_else(PoisonError::into_inner);
+    if *context == 0 {
+        // Destroyed by another comm already, NCCL falls back to its own choice.
+        return ncclResult_t::ncclInternalError;
+    }
+
+    get_coll_info::<P>(
+        *context as *mut c_void,
+        func,
+        bytes,
+        collnet_support,
+        nvls_support,
+        pipe_ops,
+        algorithm,
+        protocol,
+        channels,
+    )
+}
+
+pub unsafe extern "C" fn destroy_v1<P: TunerPlugin>() -> ncclResult_t {
+    let context = std::mem::take(&mut *CONTEXT_V1.write().unwrap_or_else(PoisonError::into_inner));
+    if context == 0 {
+        return ncclResult_t::ncclSuccess;
+    }
+    destroy::<P>(context as *mut c_void)
+}
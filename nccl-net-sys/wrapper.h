@@ -1 +1,4 @@
 #include <nccl_net.h>
+#include <net_v7.h>
+#include <net_v8.h>
+#include <nccl_tuner.h>
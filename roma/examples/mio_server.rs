@@ -0,0 +1,63 @@
+//! Echoes Homa requests from a mio event loop, next to a TCP listener on the same port.
+//!
+//! Run with `cargo run --example mio_server --features mio -- 127.0.0.1:4000`.
+
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token};
+use roma::consts::HomaRecvmsgFlags;
+use roma::HomaSocket;
+use socket2::Domain;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+const HOMA: Token = Token(0);
+const TCP: Token = Token(1);
+
+fn main() -> roma::Result<()> {
+    let addr: SocketAddr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:4000".to_string())
+        .parse()
+        .expect("invalid address");
+
+    let mut socket = HomaSocket::new(Domain::for_address(addr), 1000)?;
+    socket.socket.bind(&addr.into())?;
+
+    let mut listener = TcpListener::bind(addr)?;
+
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut socket, HOMA, Interest::READABLE)?;
+    poll.registry()
+        .register(&mut listener, TCP, Interest::READABLE)?;
+
+    let mut events = Events::with_capacity(64);
+    let mut buf = vec![0u8; 1 << 20];
+
+    loop {
+        poll.poll(&mut events, None)?;
+
+        for event in events.iter() {
+            match event.token() {
+                HOMA => loop {
+                    let flags = HomaRecvmsgFlags::REQUEST | HomaRecvmsgFlags::NONBLOCKING;
+                    match socket.recv(&mut buf, flags, 0) {
+                        Ok((length, from, id, _)) => {
+                            socket.send(&buf[..length], from, id, 0)?;
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                    }
+                },
+                TCP => loop {
+                    match listener.accept() {
+                        Ok((_, from)) => println!("tcp connection from {}", from),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err.into()),
+                    }
+                },
+                _ => unreachable!(),
+            }
+        }
+    }
+}
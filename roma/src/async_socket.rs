@@ -0,0 +1,150 @@
+use crate::{consts, Error, HomaSocket, Result};
+use std::io::{ErrorKind, IoSlice};
+use std::net::SocketAddr;
+use tokio::io::unix::AsyncFd;
+
+/// A [`HomaSocket`] driven by the tokio reactor.
+///
+/// Receives are always issued with [`consts::HomaRecvmsgFlags::NONBLOCKING`] and
+/// retried once the socket reports readiness, instead of blocking the calling thread.
+pub struct AsyncHomaSocket {
+    inner: AsyncFd<HomaSocket>,
+}
+
+impl AsyncHomaSocket {
+    /// Registers the socket with the current tokio runtime.
+    pub fn new(socket: HomaSocket) -> Result<Self> {
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    pub fn get_ref(&self) -> &HomaSocket {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut HomaSocket {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> HomaSocket {
+        self.inner.into_inner()
+    }
+
+    pub async fn send(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+        id: u64,
+        completion_cookie: u64,
+    ) -> Result<u64> {
+        loop {
+            let mut guard = self.inner.writable().await?;
+            match guard
+                .try_io(|inner| would_block(inner.get_ref().send(buf, addr, id, completion_cookie)))
+            {
+                Ok(result) => return result?,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub async fn send_vectored(
+        &self,
+        bufs: &[IoSlice<'_>],
+        addr: SocketAddr,
+        id: u64,
+        completion_cookie: u64,
+    ) -> Result<u64> {
+        loop {
+            let mut guard = self.inner.writable().await?;
+            match guard.try_io(|inner| {
+                would_block(
+                    inner
+                        .get_ref()
+                        .send_vectored(bufs, addr, id, completion_cookie),
+                )
+            }) {
+                Ok(result) => return result?,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub async fn recv(
+        &mut self,
+        buf: &mut [u8],
+        flags: consts::HomaRecvmsgFlags,
+        id: u64,
+    ) -> Result<(usize, SocketAddr, u64, u64)> {
+        let flags = flags | consts::HomaRecvmsgFlags::NONBLOCKING;
+        loop {
+            let mut guard = self.inner.readable_mut().await?;
+            match guard.try_io(|inner| would_block(inner.get_mut().recv(buf, flags, id))) {
+                Ok(result) => return result?,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Lifts `WouldBlock` out of a result, as expected by [`tokio::io::unix::AsyncFdReadyGuard::try_io`].
+fn would_block<T>(result: Result<T>) -> std::io::Result<Result<T>> {
+    match result {
+        Err(Error::System(err)) if err.kind() == ErrorKind::WouldBlock => Err(err),
+        result => Ok(result),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use rand::{RngCore, SeedableRng};
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let server = HomaSocket::new(Domain::IPV4, 1000).unwrap();
+        let addr: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        server.socket.bind(&addr.into()).unwrap();
+        let mut server = AsyncHomaSocket::new(server).unwrap();
+
+        tokio::spawn(async move {
+            let mut bufs = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+            loop {
+                let (length, addr, id, _) = server
+                    .recv(&mut bufs, consts::HomaRecvmsgFlags::REQUEST, 0)
+                    .await
+                    .unwrap();
+                server.send(&bufs[..length], addr, id, 0).await.unwrap();
+            }
+        });
+
+        let mut client =
+            AsyncHomaSocket::new(HomaSocket::new(Domain::IPV4, 1000).unwrap()).unwrap();
+
+        let mut buf = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+        let mut i = 1;
+
+        while i < consts::HOMA_MAX_MESSAGE_LENGTH {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(i.try_into().unwrap());
+
+            let mut src = vec![0u8; i];
+
+            rng.fill_bytes(&mut src);
+
+            let id = client.send(&src, addr, 0, 0).await.unwrap();
+
+            let (length, _, _, _) = client
+                .recv(&mut buf, consts::HomaRecvmsgFlags::empty(), id)
+                .await
+                .unwrap();
+
+            assert_eq!(src.len(), length);
+            assert_eq!(src, buf[..length]);
+
+            i *= 2
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{consts, types, Error, HomaSocket, Result};
+use memmap2::{MmapMut, MmapOptions};
+use nix::sys::socket::setsockopt;
+use socket2::{Domain, Socket, Type};
+use std::collections::VecDeque;
+use std::ffi::{c_ulong, CString};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+
+/// mbind(2) policy restricting allocations to the given nodes.
+const MPOL_BIND: c_ulong = 2;
+
+/// mbind(2) flag moving pages already allocated elsewhere.
+const MPOL_MF_MOVE: c_ulong = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageSize {
+    Size2MB,
+    Size1GB,
+}
+
+impl HugePageSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            HugePageSize::Size2MB => 2 << 20,
+            HugePageSize::Size1GB => 1 << 30,
+        }
+    }
+
+    fn memfd_flags(self) -> libc::c_uint {
+        match self {
+            HugePageSize::Size2MB => libc::MFD_HUGETLB | libc::MFD_HUGE_2MB,
+            HugePageSize::Size1GB => libc::MFD_HUGETLB | libc::MFD_HUGE_1GB,
+        }
+    }
+}
+
+/// Configures the buffer region backing a [`HomaSocket`].
+///
+/// By default the region is a plain anonymous mapping, as created by [`HomaSocket::new`].
+pub struct HomaSocketBuilder {
+    domain: Domain,
+    pages: usize,
+    hugepages: Option<HugePageSize>,
+    memfd: Option<File>,
+    mlock: bool,
+    numa_node: Option<u32>,
+}
+
+impl HomaSocketBuilder {
+    pub fn new(domain: Domain, pages: usize) -> Self {
+        Self {
+            domain,
+            pages,
+            hugepages: None,
+            memfd: None,
+            mlock: false,
+            numa_node: None,
+        }
+    }
+
+    /// Backs the region with hugepages of the given size, the region length must be a
+    /// multiple of it.
+    pub fn hugepages(mut self, size: HugePageSize) -> Self {
+        self.hugepages = Some(size);
+        self
+    }
+
+    /// Maps the region from a caller supplied memfd, which is grown to the region length
+    /// if needed.
+    pub fn memfd(mut self, memfd: File) -> Self {
+        self.memfd = Some(memfd);
+        self
+    }
+
+    /// Locks the region into memory.
+    pub fn mlock(mut self, mlock: bool) -> Self {
+        self.mlock = mlock;
+        self
+    }
+
+    /// Binds the region to the given NUMA node.
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.numa_node = Some(node);
+        self
+    }
+
+    pub fn build(self) -> Result<HomaSocket> {
+        log::debug!(
+            "HomaSocketBuilder::build(domain: {:?}, pages: {}, hugepages: {:?}, memfd: {:?}, mlock: {}, numa_node: {:?})",
+            self.domain,
+            self.pages,
+            self.hugepages,
+            self.memfd.as_ref().map(AsRawFd::as_raw_fd),
+            self.mlock,
+            self.numa_node,
+        );
+
+        let length = self.pages * consts::HOMA_BPAGE_SIZE;
+
+        if length == 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "empty buffer region").into());
+        }
+
+        if let Some(size) = self.hugepages {
+            if length & (size.bytes() - 1) != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "buffer region is not a multiple of the hugepage size",
+                )
+                .into());
+            }
+        }
+
+        let socket = Socket::new_raw(self.domain, Type::DGRAM, Some(consts::IPPROTO_HOMA.into()))
+            .map_err(|err| Error::from_io(err, 0))?;
+
+        let mut buffer = self.map(length)?;
+
+        if let Some(node) = self.numa_node {
+            bind_node(&mut buffer, node)?;
+        }
+
+        if self.mlock {
+            buffer.lock()?;
+        }
+
+        setsockopt(socket.as_raw_fd(), types::HomaBuf, &buffer)?;
+
+        Ok(HomaSocket {
+            socket,
+            buffer: Arc::new(buffer),
+            backlog: VecDeque::default(),
+            occupancy: Arc::default(),
+        })
+    }
+
+    fn map(&self, length: usize) -> io::Result<MmapMut> {
+        let memfd = match (&self.memfd, self.hugepages) {
+            (Some(memfd), _) => Some(memfd.try_clone()?),
+            (None, Some(size)) => Some(hugetlb_memfd(size)?),
+            (None, None) => None,
+        };
+
+        match memfd {
+            Some(memfd) => {
+                if memfd.metadata()?.len() < length as u64 {
+                    memfd.set_len(length as u64)?;
+                }
+                unsafe { MmapOptions::new().len(length).map_mut(&memfd) }
+            }
+            None => MmapOptions::new().len(length).map_anon(),
+        }
+    }
+}
+
+fn hugetlb_memfd(size: HugePageSize) -> io::Result<File> {
+    let name = CString::new("roma").unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | size.memfd_flags()) };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn bind_node(buffer: &mut MmapMut, node: u32) -> io::Result<()> {
+    let bits = c_ulong::BITS as usize;
+    let node = node as usize;
+
+    let mut nodemask = vec![0 as c_ulong; node / bits + 1];
+    nodemask[node / bits] |= 1 << (node % bits);
+
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            nodemask.len() * bits + 1,
+            MPOL_MF_MOVE,
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    #[test]
+    fn memfd() {
+        let name = std::ffi::CString::new("test").unwrap();
+        let memfd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(memfd >= 0);
+        let memfd = unsafe { <std::fs::File as std::os::fd::FromRawFd>::from_raw_fd(memfd) };
+
+        let mut socket = HomaSocketBuilder::new(Domain::IPV4, 100)
+            .memfd(memfd)
+            .mlock(true)
+            .build()
+            .unwrap();
+
+        let addr: SocketAddr = "127.0.0.1:4004".parse().unwrap();
+
+        socket.socket.bind(&addr.into()).unwrap();
+
+        let mut buf = [0u8; 16];
+
+        let id = socket.send(b"ping", addr, 0, 0).unwrap();
+
+        let (length, from, rid, _) = socket
+            .recv(&mut buf, consts::HomaRecvmsgFlags::REQUEST, 0)
+            .unwrap();
+        assert_eq!(&buf[..length], b"ping");
+
+        socket.send(b"pong", from, rid, 0).unwrap();
+
+        let (length, _, _, _) = socket
+            .recv(&mut buf, consts::HomaRecvmsgFlags::empty(), id)
+            .unwrap();
+        assert_eq!(&buf[..length], b"pong");
+    }
+
+    #[test]
+    fn misaligned_hugepages() {
+        let err = HomaSocketBuilder::new(Domain::IPV4, 1)
+            .hugepages(HugePageSize::Size2MB)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
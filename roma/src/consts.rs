@@ -6,6 +6,9 @@ pub const IPPROTO_HOMA: c_int = 0xFD;
 /// Maximum bytes of payload in a Homa request or response message.
 pub const HOMA_MAX_MESSAGE_LENGTH: usize = 1000000;
 
+/// Maximum bytes of payload a send may carry, every message also holds a trailing tag byte.
+pub const HOMA_MAX_PAYLOAD_LENGTH: usize = HOMA_MAX_MESSAGE_LENGTH - 1;
+
 /// Number of bytes in pages used for receive buffers. Must be power of two.
 pub const HOMA_BPAGE_SIZE: usize = 1 << 16;
 
@@ -0,0 +1,547 @@
+use crate::{consts, transport::HomaTransport, Error, Result};
+use socket2::Domain;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::ffi::c_int;
+use std::io::{self, ErrorKind, IoSlice};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+
+/// First port handed out to sockets bound to port 0, mirroring Homa's client port range.
+const EPHEMERAL_PORT_START: u16 = 0x8000;
+
+/// All live emulated sockets of this process, keyed by port.
+static ENDPOINTS: Mutex<BTreeMap<u16, Weak<Endpoint>>> = Mutex::new(BTreeMap::new());
+
+struct Message {
+    addr: SocketAddr,
+    id: u64,
+    completion_cookie: u64,
+    payload: std::result::Result<Vec<u8>, c_int>,
+}
+
+struct Outgoing {
+    addr: SocketAddr,
+    completion_cookie: u64,
+}
+
+#[derive(Default)]
+struct State {
+    /// Requests that have arrived but were not yet received.
+    requests: VecDeque<Message>,
+    /// Client RPCs that completed, either with a response or an error.
+    responses: VecDeque<Message>,
+    /// Client RPCs still waiting for a response, keyed by id.
+    outgoing: BTreeMap<u64, Outgoing>,
+    /// Server RPCs that were received but not yet responded to.
+    incoming: HashSet<(SocketAddr, u64)>,
+    next_id: u64,
+    shutdown: bool,
+}
+
+struct Endpoint {
+    addr: SocketAddr,
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+impl Endpoint {
+    fn complete(&self, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.outgoing.remove(&message.id).is_some() {
+            state.responses.push_back(message);
+            self.ready.notify_all();
+        }
+    }
+
+    fn deliver(&self, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push_back(message);
+        self.ready.notify_all();
+    }
+}
+
+impl Drop for Endpoint {
+    /// Fails every client RPC still waiting on a request this endpoint will never answer.
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        let requests = std::mem::take(&mut state.requests);
+        let incoming = std::mem::take(&mut state.incoming);
+        let pending = requests
+            .into_iter()
+            .map(|message| (message.addr, message.id))
+            .chain(incoming);
+
+        for (addr, id) in pending {
+            if let Some(client) = EmulatedSocket::lookup(addr.port()) {
+                let completion_cookie = match client.state.lock().unwrap().outgoing.get(&id) {
+                    Some(outgoing) => outgoing.completion_cookie,
+                    None => continue,
+                };
+                client.complete(Message {
+                    addr: self.addr,
+                    id,
+                    completion_cookie,
+                    payload: Err(libc::ETIMEDOUT),
+                });
+            }
+        }
+    }
+}
+
+/// A userspace stand-in for [`crate::HomaSocket`] that exchanges RPCs between sockets of
+/// the same process, for testing on machines without the Homa kernel module.
+///
+/// Sockets are addressed by port only, any IP address reaches the socket bound to a port.
+/// RPCs to ports nobody is bound to fail with `ETIMEDOUT`, as they would eventually with Homa.
+pub struct EmulatedSocket {
+    endpoint: Arc<Endpoint>,
+}
+
+impl EmulatedSocket {
+    pub fn new(domain: Domain) -> Result<Self> {
+        log::debug!("EmulatedSocket::new(domain: {:?})", domain);
+
+        let ip = if domain == Domain::IPV6 {
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        } else {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        };
+
+        Ok(Self {
+            endpoint: Self::register(SocketAddr::new(ip, 0))?,
+        })
+    }
+
+    fn register(addr: SocketAddr) -> Result<Arc<Endpoint>> {
+        let mut endpoints = ENDPOINTS.lock().unwrap();
+        endpoints.retain(|_, endpoint| endpoint.strong_count() > 0);
+
+        let port = if addr.port() == 0 {
+            (EPHEMERAL_PORT_START..=u16::MAX)
+                .find(|port| !endpoints.contains_key(port))
+                .ok_or_else(|| io::Error::from(ErrorKind::AddrInUse))?
+        } else if endpoints.contains_key(&addr.port()) {
+            return Err(io::Error::from(ErrorKind::AddrInUse).into());
+        } else {
+            addr.port()
+        };
+
+        let ip = if addr.ip().is_unspecified() {
+            match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            }
+        } else {
+            addr.ip()
+        };
+
+        let endpoint = Arc::new(Endpoint {
+            addr: SocketAddr::new(ip, port),
+            state: Mutex::new(State {
+                next_id: 2,
+                ..Default::default()
+            }),
+            ready: Condvar::new(),
+        });
+        endpoints.insert(port, Arc::downgrade(&endpoint));
+
+        Ok(endpoint)
+    }
+
+    fn lookup(port: u16) -> Option<Arc<Endpoint>> {
+        ENDPOINTS.lock().unwrap().get(&port).and_then(Weak::upgrade)
+    }
+
+    fn take(
+        state: &mut State,
+        flags: consts::HomaRecvmsgFlags,
+        id: u64,
+    ) -> Result<Option<Message>> {
+        if state.shutdown {
+            return Err(io::Error::from_raw_os_error(libc::ESHUTDOWN).into());
+        }
+
+        if id != 0 {
+            if let Some(index) = state.responses.iter().position(|m| m.id == id) {
+                return Ok(state.responses.remove(index));
+            }
+            if !state.outgoing.contains_key(&id) {
+                return Err(Error::UnknownRpc { id });
+            }
+            return Ok(None);
+        }
+
+        if !flags.intersects(consts::HomaRecvmsgFlags::REQUEST | consts::HomaRecvmsgFlags::RESPONSE)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+
+        if flags.contains(consts::HomaRecvmsgFlags::REQUEST) {
+            if let Some(message) = state.requests.pop_front() {
+                state.incoming.insert((message.addr, message.id));
+                return Ok(Some(message));
+            }
+        }
+
+        if flags.contains(consts::HomaRecvmsgFlags::RESPONSE) {
+            if let Some(message) = state.responses.pop_front() {
+                return Ok(Some(message));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl HomaTransport for EmulatedSocket {
+    fn bind(&mut self, addr: SocketAddr) -> Result<()> {
+        log::debug!("EmulatedSocket::bind(addr: {})", addr);
+
+        self.endpoint = Self::register(addr)?;
+
+        Ok(())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.addr)
+    }
+
+    fn send(&self, buf: &[u8], addr: SocketAddr, id: u64, completion_cookie: u64) -> Result<u64> {
+        self.send_vectored(&[IoSlice::new(buf)], addr, id, completion_cookie)
+    }
+
+    fn send_vectored(
+        &self,
+        bufs: &[IoSlice<'_>],
+        addr: SocketAddr,
+        id: u64,
+        completion_cookie: u64,
+    ) -> Result<u64> {
+        log::debug!(
+            "EmulatedSocket::send_vectored(bufs.len(): {}, addr: {}, id: {}, completion_cookie: {})",
+            bufs.len(),
+            addr,
+            id,
+            completion_cookie
+        );
+
+        let payload = bufs.iter().map(|buf| &**buf).collect::<Vec<_>>().concat();
+
+        // The kernel limits the message including the tag byte HomaSocket appends.
+        if payload.len() + 1 > consts::HOMA_MAX_MESSAGE_LENGTH {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+
+        if id != 0 {
+            // A response: silently dropped if the client is no longer interested.
+            let known = {
+                let mut state = self.endpoint.state.lock().unwrap();
+                if state.shutdown {
+                    return Err(io::Error::from_raw_os_error(libc::ESHUTDOWN).into());
+                }
+                state.incoming.remove(&(addr, id))
+            };
+            if known {
+                if let Some(client) = Self::lookup(addr.port()) {
+                    let completion_cookie = client
+                        .state
+                        .lock()
+                        .unwrap()
+                        .outgoing
+                        .get(&id)
+                        .map(|outgoing| outgoing.completion_cookie);
+                    if let Some(completion_cookie) = completion_cookie {
+                        client.complete(Message {
+                            addr: self.endpoint.addr,
+                            id,
+                            completion_cookie,
+                            payload: Ok(payload),
+                        });
+                    }
+                }
+            }
+            return Ok(id);
+        }
+
+        let id = {
+            let mut state = self.endpoint.state.lock().unwrap();
+            if state.shutdown {
+                return Err(io::Error::from_raw_os_error(libc::ESHUTDOWN).into());
+            }
+            let id = state.next_id;
+            state.next_id += 2;
+            state.outgoing.insert(
+                id,
+                Outgoing {
+                    addr,
+                    completion_cookie,
+                },
+            );
+            id
+        };
+
+        match Self::lookup(addr.port()) {
+            Some(server) => server.deliver(Message {
+                addr: self.endpoint.addr,
+                id,
+                completion_cookie: 0,
+                payload: Ok(payload),
+            }),
+            None => self.endpoint.complete(Message {
+                addr,
+                id,
+                completion_cookie,
+                payload: Err(libc::ETIMEDOUT),
+            }),
+        }
+
+        Ok(id)
+    }
+
+    fn recv(
+        &mut self,
+        buf: &mut [u8],
+        flags: consts::HomaRecvmsgFlags,
+        id: u64,
+    ) -> Result<(usize, SocketAddr, u64, u64)> {
+        log::debug!(
+            "EmulatedSocket::recv(buf.len(): {}, flags: {:?}, id: {})",
+            buf.len(),
+            flags,
+            id,
+        );
+
+        let mut state = self.endpoint.state.lock().unwrap();
+        let message = loop {
+            if let Some(message) = Self::take(&mut state, flags, id)? {
+                break message;
+            }
+            if flags.contains(consts::HomaRecvmsgFlags::NONBLOCKING) {
+                return Err(io::Error::from_raw_os_error(libc::EAGAIN).into());
+            }
+            state = self.endpoint.ready.wait(state).unwrap();
+        };
+        drop(state);
+
+        let payload = message
+            .payload
+            .map_err(|code| Error::from_rpc(code, message.id))?;
+
+        if buf.len() < payload.len() {
+            return Err(Error::Truncated {
+                length: payload.len(),
+            });
+        }
+
+        buf[..payload.len()].copy_from_slice(&payload);
+
+        Ok((
+            payload.len(),
+            message.addr,
+            message.id,
+            message.completion_cookie,
+        ))
+    }
+
+    fn abort(&self, id: u64, error: c_int) -> Result<()> {
+        log::debug!("EmulatedSocket::abort(id: {}, error: {})", id, error);
+
+        let mut state = self.endpoint.state.lock().unwrap();
+
+        let ids: Vec<u64> = if id == 0 {
+            state.outgoing.keys().copied().collect()
+        } else if state.outgoing.contains_key(&id) {
+            vec![id]
+        } else if error == 0 && state.responses.iter().any(|m| m.id == id) {
+            state.responses.retain(|m| m.id != id);
+            return Ok(());
+        } else {
+            return Err(Error::UnknownRpc { id });
+        };
+
+        for id in ids {
+            let outgoing = state.outgoing.remove(&id).unwrap();
+            if error != 0 {
+                state.responses.push_back(Message {
+                    addr: outgoing.addr,
+                    id,
+                    completion_cookie: outgoing.completion_cookie,
+                    payload: Err(error),
+                });
+            }
+        }
+        if id == 0 && error == 0 {
+            state.responses.clear();
+        }
+        self.endpoint.ready.notify_all();
+
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        log::debug!("EmulatedSocket::shutdown()");
+
+        let mut state = self.endpoint.state.lock().unwrap();
+        state.shutdown = true;
+        self.endpoint.ready.notify_all();
+
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            endpoint: self.endpoint.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::consts::HomaRecvmsgFlags;
+    use crate::*;
+    use rand::{RngCore, SeedableRng};
+    use std::io::ErrorKind;
+
+    fn server() -> (EmulatedSocket, SocketAddr) {
+        let mut socket = EmulatedSocket::new(Domain::IPV4).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (mut server, addr) = server();
+
+        let _server = std::thread::spawn(move || {
+            let mut bufs = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+            loop {
+                let (length, addr, id, _) = server
+                    .recv(&mut bufs, HomaRecvmsgFlags::REQUEST, 0)
+                    .unwrap();
+                server.send(&bufs[..length], addr, id, 0).unwrap();
+            }
+        });
+
+        let mut socket = EmulatedSocket::new(Domain::IPV4).unwrap();
+
+        let mut buf = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+        let mut i = 1;
+
+        while i < consts::HOMA_MAX_MESSAGE_LENGTH {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(i.try_into().unwrap());
+
+            let mut src = vec![0u8; i];
+
+            rng.fill_bytes(&mut src);
+
+            let id = socket.send(&src, addr, 0, i.try_into().unwrap()).unwrap();
+
+            let (length, _, rid, cookie) = socket
+                .recv(&mut buf, HomaRecvmsgFlags::empty(), id)
+                .unwrap();
+
+            assert_eq!(id, rid);
+            assert_eq!(cookie, i.try_into().unwrap());
+            assert_eq!(src.len(), length);
+            assert_eq!(src, buf[..length]);
+
+            i *= 2
+        }
+    }
+
+    #[test]
+    fn oversized() {
+        let (_server, addr) = server();
+        let socket = EmulatedSocket::new(Domain::IPV4).unwrap();
+
+        let buf = vec![0u8; consts::HOMA_MAX_PAYLOAD_LENGTH + 1];
+        let err = socket.send(&buf, addr, 0, 0).unwrap_err();
+        assert!(matches!(err, Error::System(err) if err.raw_os_error() == Some(libc::EINVAL)));
+
+        socket
+            .send(&buf[..consts::HOMA_MAX_PAYLOAD_LENGTH], addr, 0, 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn filtering() {
+        let (mut server, addr) = server();
+        let mut client = EmulatedSocket::new(Domain::IPV4).unwrap();
+        let mut buf = [0u8; 16];
+
+        let id = client.send(b"ping", addr, 0, 7).unwrap();
+
+        let err = server
+            .recv(
+                &mut buf,
+                HomaRecvmsgFlags::RESPONSE | HomaRecvmsgFlags::NONBLOCKING,
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        let (length, from, rid, _) = server.recv(&mut buf, HomaRecvmsgFlags::REQUEST, 0).unwrap();
+        assert_eq!(&buf[..length], b"ping");
+        assert_eq!(from, client.local_addr().unwrap());
+        assert_eq!(rid, id);
+
+        server.send(b"pong", from, rid, 0).unwrap();
+
+        let (length, _, rid, cookie) = client
+            .recv(&mut buf, HomaRecvmsgFlags::RESPONSE, 0)
+            .unwrap();
+        assert_eq!(&buf[..length], b"pong");
+        assert_eq!(rid, id);
+        assert_eq!(cookie, 7);
+
+        let err = client
+            .recv(&mut buf, HomaRecvmsgFlags::empty(), id)
+            .unwrap_err();
+        assert!(matches!(err, Error::UnknownRpc { id: rid } if rid == id));
+    }
+
+    #[test]
+    fn abort() {
+        let (mut server, addr) = server();
+        let mut client = EmulatedSocket::new(Domain::IPV4).unwrap();
+        let mut buf = [0u8; 16];
+
+        let first = client.send(b"first", addr, 0, 0).unwrap();
+        let second = client.send(b"second", addr, 0, 0).unwrap();
+
+        client.abort(first, libc::ECANCELED).unwrap();
+        let err = client
+            .recv(&mut buf, HomaRecvmsgFlags::empty(), first)
+            .unwrap_err();
+        assert!(matches!(err, Error::Aborted { id, code: libc::ECANCELED } if id == first));
+
+        client.abort(0, 0).unwrap();
+        let err = client
+            .recv(&mut buf, HomaRecvmsgFlags::empty(), second)
+            .unwrap_err();
+        assert!(matches!(err, Error::UnknownRpc { id } if id == second));
+
+        // The server may still answer, the response is dropped.
+        let (_, from, rid, _) = server.recv(&mut buf, HomaRecvmsgFlags::REQUEST, 0).unwrap();
+        server.send(b"late", from, rid, 0).unwrap();
+        let err = client
+            .recv(
+                &mut buf,
+                HomaRecvmsgFlags::RESPONSE | HomaRecvmsgFlags::NONBLOCKING,
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        let unbound = client
+            .send(b"nobody", "127.0.0.1:1".parse().unwrap(), 0, 0)
+            .unwrap();
+        let err = client
+            .recv(&mut buf, HomaRecvmsgFlags::empty(), unbound)
+            .unwrap_err();
+        assert!(matches!(err, Error::TimedOut { id } if id == unbound));
+    }
+}
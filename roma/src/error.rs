@@ -0,0 +1,152 @@
+use std::ffi::c_int;
+use std::io::ErrorKind;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// The RPC was aborted with the given error code, e.g. through [`crate::HomaSocket::abort`].
+    #[error("rpc {id} aborted: {}", std::io::Error::from_raw_os_error(*code))]
+    Aborted { id: u64, code: c_int },
+    #[error("rpc {id} timed out")]
+    TimedOut { id: u64 },
+    #[error("unknown rpc {id}")]
+    UnknownRpc { id: u64 },
+    /// The receive buffer was too small, `length` is the size of the message.
+    #[error("message truncated, {length} bytes required")]
+    Truncated { length: usize },
+    #[error("homa kernel module not loaded")]
+    ModuleMissing,
+    #[error(transparent)]
+    System(#[from] std::io::Error),
+}
+
+impl Error {
+    /// Decodes the error of a failed recv or abort issued for RPC `id` (0 if none), an
+    /// invalid argument then means the RPC is not known.
+    pub(crate) fn from_io(err: std::io::Error, id: u64) -> Self {
+        match err.raw_os_error() {
+            Some(libc::EPROTONOSUPPORT) => Error::ModuleMissing,
+            Some(libc::EINVAL) if id != 0 => Error::UnknownRpc { id },
+            _ => Error::System(err),
+        }
+    }
+
+    /// Decodes the error code an RPC was completed with.
+    pub(crate) fn from_rpc(code: c_int, id: u64) -> Self {
+        match code {
+            libc::ETIMEDOUT => Error::TimedOut { id },
+            code => Error::Aborted { id, code },
+        }
+    }
+
+    /// Decodes the error of a failed recvmsg issued for RPC `requested`, after which the
+    /// kernel left `returned` in the arguments.
+    ///
+    /// The kernel copies the arguments back even when the call itself failed, so the
+    /// requested id comes back unchanged for errors that concern no RPC.
+    pub(crate) fn from_recv(err: std::io::Error, requested: u64, returned: u64) -> Self {
+        let code = err.raw_os_error().unwrap_or(0);
+        let call = matches!(
+            code,
+            libc::EAGAIN | libc::EINTR | libc::EINVAL | libc::EFAULT | libc::ENOMEM
+        );
+        if returned == 0 || (returned == requested && call) {
+            Self::from_io(err, requested)
+        } else {
+            Self::from_rpc(code, returned)
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Aborted { .. } => ErrorKind::ConnectionAborted,
+            Error::TimedOut { .. } => ErrorKind::TimedOut,
+            Error::UnknownRpc { .. } => ErrorKind::NotFound,
+            Error::Truncated { .. } => ErrorKind::OutOfMemory,
+            Error::ModuleMissing => ErrorKind::Unsupported,
+            Error::System(err) => err.kind(),
+        }
+    }
+}
+
+impl From<nix::errno::Errno> for Error {
+    fn from(errno: nix::errno::Errno) -> Self {
+        Error::System(errno.into())
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::System(err) => err,
+            err => std::io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use crate::Error;
+
+    #[test]
+    fn decode() {
+        let err = |errno| std::io::Error::from_raw_os_error(errno);
+
+        assert!(matches!(
+            Error::from_io(err(libc::EPROTONOSUPPORT), 0),
+            Error::ModuleMissing
+        ));
+        assert!(matches!(
+            Error::from_io(err(libc::EINVAL), 42),
+            Error::UnknownRpc { id: 42 }
+        ));
+        assert!(matches!(
+            Error::from_io(err(libc::EINVAL), 0),
+            Error::System(_)
+        ));
+        assert!(matches!(
+            Error::from_recv(err(libc::EAGAIN), 42, 42),
+            Error::System(err) if err.kind() == std::io::ErrorKind::WouldBlock
+        ));
+        assert!(matches!(
+            Error::from_recv(err(libc::EINVAL), 42, 42),
+            Error::UnknownRpc { id: 42 }
+        ));
+        assert!(matches!(
+            Error::from_recv(err(libc::ETIMEDOUT), 42, 42),
+            Error::TimedOut { id: 42 }
+        ));
+        assert!(matches!(
+            Error::from_recv(err(libc::EINVAL), 0, 7),
+            Error::Aborted {
+                id: 7,
+                code: libc::EINVAL
+            }
+        ));
+        assert!(matches!(
+            Error::from_recv(err(libc::EINTR), 0, 0),
+            Error::System(_)
+        ));
+        assert!(matches!(
+            Error::from_rpc(libc::ETIMEDOUT, 42),
+            Error::TimedOut { id: 42 }
+        ));
+        assert!(matches!(
+            Error::from_rpc(libc::ECANCELED, 42),
+            Error::Aborted {
+                id: 42,
+                code: libc::ECANCELED
+            }
+        ));
+
+        // The errno text is kept when logged.
+        assert_eq!(
+            Error::System(err(libc::EBADF)).to_string(),
+            err(libc::EBADF).to_string()
+        );
+
+        let err: std::io::Error = Error::Truncated { length: 42 }.into();
+        assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+    }
+}
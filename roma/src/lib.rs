@@ -1,45 +1,104 @@
 #![feature(int_roundings)]
 #![feature(default_free_fn)]
 
-use memmap2::{MmapMut, MmapOptions};
-use nix::sys::socket::setsockopt;
-use socket2::{Domain, SockAddr, Socket, Type};
+use memmap2::MmapMut;
+use socket2::{Domain, SockAddr, Socket};
 use std::cmp::min;
 use std::collections::VecDeque;
 use std::ffi::c_int;
-use std::io::{Error, ErrorKind, IoSlice, Result, Write};
+use std::io::{self, IoSlice, Read, Write};
 use std::mem::size_of_val;
 use std::net::SocketAddr;
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, RawFd};
 use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
+#[cfg(feature = "tokio")]
+pub mod async_socket;
+pub mod builder;
 pub mod consts;
+pub mod emulator;
+pub mod error;
+pub mod rpc;
+#[cfg(feature = "mio")]
+mod source;
+pub mod transport;
 pub mod types;
 
+#[cfg(feature = "tokio")]
+pub use async_socket::AsyncHomaSocket;
+pub use builder::{HomaSocketBuilder, HugePageSize};
+pub use emulator::EmulatedSocket;
+pub use error::{Error, Result};
+pub use transport::HomaTransport;
+
+/// A Homa socket receiving into a buffer region of 64KiB bpages.
+///
+/// The kernel lends bpages out of the region for every received message. They are
+/// handed back along with the next receive issued on the same handle, or explicitly
+/// through [`HomaSocket::release_buffers`], which should be called before a handle goes
+/// idle. [`HomaSocket::occupancy`] reports how many bpages are currently lent out.
 pub struct HomaSocket {
     pub socket: Socket,
-    buffer: MmapMut,
+    buffer: Arc<MmapMut>,
+    /// bpages of this handle waiting to be handed back to the kernel.
     backlog: VecDeque<u32>,
+    /// bpages lent out of the region, shared by all handles of the socket.
+    occupancy: Arc<AtomicUsize>,
 }
 
 impl HomaSocket {
     pub fn new(domain: Domain, pages: usize) -> Result<Self> {
         log::debug!("HomaSocket::new(domain: {:?}, pages: {})", domain, pages);
 
-        let socket = Socket::new_raw(domain, Type::DGRAM, Some(consts::IPPROTO_HOMA.into()))?;
-
-        let length = pages * consts::HOMA_BPAGE_SIZE;
-        let buffer = MmapOptions::new().len(length).map_anon()?;
-
-        setsockopt(socket.as_raw_fd(), types::HomaBuf, &buffer).unwrap();
+        HomaSocketBuilder::new(domain, pages).build()
+    }
 
+    /// Creates a new handle to the same Homa socket, sharing its buffer region.
+    ///
+    /// Handles may receive concurrently from different threads, e.g. a pool of workers
+    /// serving requests arriving on one port.
+    pub fn try_clone(&self) -> Result<Self> {
         Ok(Self {
-            socket,
-            buffer,
+            socket: self.socket.try_clone()?,
+            buffer: self.buffer.clone(),
             backlog: VecDeque::default(),
+            occupancy: self.occupancy.clone(),
         })
     }
 
+    /// The number of bpages in the buffer region.
+    pub fn capacity(&self) -> usize {
+        self.buffer.len() / consts::HOMA_BPAGE_SIZE
+    }
+
+    /// The number of bpages currently lent out of the buffer region, across all handles
+    /// of the socket. These are unavailable to the kernel for incoming messages.
+    pub fn occupancy(&self) -> usize {
+        self.occupancy.load(Ordering::Relaxed)
+    }
+
+    /// Hands the bpages of all received messages already consumed through this handle
+    /// back to the kernel, without waiting for a message.
+    pub fn release_buffers(&mut self) -> Result<()> {
+        log::debug!(
+            "HomaSocket::release_buffers(backlog: {})",
+            self.backlog.len()
+        );
+
+        while !self.backlog.is_empty() {
+            // Neither requests nor responses are asked for, so this only returns bpages.
+            match self.recvmsg(consts::HomaRecvmsgFlags::NONBLOCKING, 0) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+                Ok(_) => unreachable!("received a message without asking for one"),
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn send(
         &self,
         buf: &[u8],
@@ -55,10 +114,41 @@ impl HomaSocket {
             completion_cookie
         );
 
+        self.sendmsg(&[IoSlice::new(buf)], addr, id, completion_cookie)
+    }
+
+    /// Sends a message gathered from several buffers, without first concatenating them.
+    pub fn send_vectored(
+        &self,
+        bufs: &[IoSlice<'_>],
+        addr: SocketAddr,
+        id: u64,
+        completion_cookie: u64,
+    ) -> Result<u64> {
+        log::debug!(
+            "HomaSocket::send_vectored(bufs.len(): {}, addr: {}, id: {}, completion_cookie: {})",
+            bufs.len(),
+            addr,
+            id,
+            completion_cookie
+        );
+
+        self.sendmsg(bufs, addr, id, completion_cookie)
+    }
+
+    /// Issues a single sendmsg, appending the trailing tag to the given buffers.
+    fn sendmsg(
+        &self,
+        bufs: &[IoSlice<'_>],
+        addr: SocketAddr,
+        id: u64,
+        completion_cookie: u64,
+    ) -> Result<u64> {
         let addr = SockAddr::from(addr);
 
         let tag = [0u8; 1];
-        let iov = vec![IoSlice::new(buf), IoSlice::new(&tag)];
+        let mut iov = bufs.to_vec();
+        iov.push(IoSlice::new(&tag));
 
         let mut sendmsg_args = types::homa_sendmsg_args {
             id,
@@ -78,7 +168,7 @@ impl HomaSocket {
         let result = unsafe { libc::sendmsg(self.socket.as_raw_fd(), &mut hdr, 0) };
 
         if result < 0 {
-            return Err(Error::last_os_error());
+            return Err(Error::from_io(io::Error::last_os_error(), 0));
         }
 
         Ok(sendmsg_args.id)
@@ -97,6 +187,70 @@ impl HomaSocket {
             id,
         );
 
+        let (length, addr, recvmsg_args) = self.recvmsg(flags, id)?;
+
+        let offsets = &recvmsg_args.bpage_offsets[..recvmsg_args.num_bpages as usize];
+
+        // The message is consumed either way, its bpages must not be lost.
+        self.backlog.extend(offsets);
+
+        if buf.len() < length {
+            return Err(Error::Truncated { length });
+        }
+
+        let mut buf = &mut buf[..length];
+        let mut vectored = vec![];
+        for &offset in offsets {
+            unsafe {
+                let data = self.buffer.as_ptr().offset(offset.try_into().unwrap());
+                let data = IoSlice::new(slice::from_raw_parts(data, consts::HOMA_BPAGE_SIZE));
+                vectored.push(data);
+            }
+        }
+        let len = buf.write_vectored(&mut vectored).unwrap();
+        assert_eq!(len, length);
+
+        Ok((
+            length,
+            addr,
+            recvmsg_args.id,
+            recvmsg_args.completion_cookie,
+        ))
+    }
+
+    /// Receives a message without copying it out of the buffer region.
+    ///
+    /// The bpages holding the message are handed back to the kernel only after the
+    /// returned [`HomaMessage`] is dropped.
+    pub fn recv_borrowed(
+        &mut self,
+        flags: consts::HomaRecvmsgFlags,
+        id: u64,
+    ) -> Result<HomaMessage<'_>> {
+        log::debug!("HomaSocket::recv_borrowed(flags: {:?}, id: {})", flags, id,);
+
+        let (length, addr, recvmsg_args) = self.recvmsg(flags, id)?;
+
+        let offsets = recvmsg_args.bpage_offsets[..recvmsg_args.num_bpages as usize].to_vec();
+
+        Ok(HomaMessage {
+            socket: self,
+            offsets,
+            length,
+            position: 0,
+            addr,
+            id: recvmsg_args.id,
+            completion_cookie: recvmsg_args.completion_cookie,
+        })
+    }
+
+    /// Issues a single recvmsg, returning the payload length (excluding the trailing tag),
+    /// the peer address and the filled in recvmsg arguments.
+    fn recvmsg(
+        &mut self,
+        flags: consts::HomaRecvmsgFlags,
+        id: u64,
+    ) -> Result<(usize, SocketAddr, types::homa_recvmsg_args)> {
         let num_bpages = min(self.backlog.len(), consts::HOMA_MAX_BPAGES);
         let bpages: Vec<u32> = self.backlog.drain(0..num_bpages).collect();
 
@@ -132,47 +286,131 @@ impl HomaSocket {
             )
         };
 
+        // The kernel takes the returned bpages back before it can fail, short of
+        // malformed arguments.
+        self.occupancy.fetch_sub(num_bpages, Ordering::Relaxed);
+
         if length < 0 {
-            return Err(Error::last_os_error());
+            return Err(Error::from_recv(
+                io::Error::last_os_error(),
+                id,
+                recvmsg_args.id,
+            ));
         }
 
         let length: usize = length.try_into().unwrap();
 
-        if buf.len() < length - 1 {
-            return Err(Error::new(ErrorKind::OutOfMemory, "buffer too small"));
-        }
-
-        let mut buf = &mut buf[..length - 1];
-        let mut vectored = vec![];
-        for i in 0..recvmsg_args.num_bpages as usize {
-            let offset = recvmsg_args.bpage_offsets[i];
-            self.backlog.push_back(offset);
-            unsafe {
-                let data = self.buffer.as_ptr().offset(offset.try_into().unwrap());
-                let data = IoSlice::new(slice::from_raw_parts(data, consts::HOMA_BPAGE_SIZE));
-                vectored.push(data);
-            }
-        }
-        let len = buf.write_vectored(&mut vectored).unwrap();
-        assert_eq!(len, length - 1);
+        self.occupancy
+            .fetch_add(recvmsg_args.num_bpages as usize, Ordering::Relaxed);
 
         let addr = unsafe { SockAddr::new(addr, size_of_val(&addr).try_into().unwrap()) };
 
-        Ok((
-            length - 1,
-            addr.as_socket().unwrap(),
-            recvmsg_args.id,
-            recvmsg_args.completion_cookie,
-        ))
+        Ok((length - 1, addr.as_socket().unwrap(), recvmsg_args))
     }
 
-    pub fn abort(&self, id: u64, error: c_int) -> nix::Result<i32> {
+    /// Aborts the client RPC `id`, or all client RPCs of the socket if `id` is 0.
+    ///
+    /// With a non-zero `error` the RPCs complete with [`Error::Aborted`] carrying it,
+    /// otherwise they are discarded silently.
+    pub fn abort(&self, id: u64, error: c_int) -> Result<()> {
         let mut abort_args = types::homa_abort_args::new(id, error);
         unsafe { types::homa_abort(self.socket.as_raw_fd(), &mut abort_args) }
+            .map_err(|errno| Error::from_io(errno.into(), id))?;
+        Ok(())
     }
 
-    pub fn freeze(&self) -> nix::Result<i32> {
-        unsafe { types::homa_freeze(self.socket.as_raw_fd()) }
+    pub fn freeze(&self) -> Result<()> {
+        unsafe { types::homa_freeze(self.socket.as_raw_fd()) }?;
+        Ok(())
+    }
+}
+
+impl Drop for HomaSocket {
+    fn drop(&mut self) {
+        // Other handles may keep the socket open, do not leak bpages out from under them.
+        if let Err(err) = self.release_buffers() {
+            log::warn!("HomaSocket: failed to release buffers: {}", err);
+        }
+    }
+}
+
+impl AsRawFd for HomaSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// A message lent out of a [`HomaSocket`]'s buffer region by [`HomaSocket::recv_borrowed`].
+pub struct HomaMessage<'a> {
+    socket: &'a mut HomaSocket,
+    offsets: Vec<u32>,
+    length: usize,
+    position: usize,
+    addr: SocketAddr,
+    id: u64,
+    completion_cookie: u64,
+}
+
+impl<'a> HomaMessage<'a> {
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn completion_cookie(&self) -> u64 {
+        self.completion_cookie
+    }
+
+    /// The message payload, one slice per bpage.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.offsets.len())
+            .map(|i| self.chunk(i))
+            .filter(|chunk| !chunk.is_empty())
+    }
+
+    fn chunk(&self, index: usize) -> &[u8] {
+        let start = index * consts::HOMA_BPAGE_SIZE;
+        let len = min(self.length.saturating_sub(start), consts::HOMA_BPAGE_SIZE);
+        unsafe {
+            let data = self
+                .socket
+                .buffer
+                .as_ptr()
+                .offset(self.offsets[index].try_into().unwrap());
+            slice::from_raw_parts(data, len)
+        }
+    }
+}
+
+impl<'a> Read for HomaMessage<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() && self.position < self.length {
+            let chunk = &self.chunk(self.position / consts::HOMA_BPAGE_SIZE)
+                [self.position % consts::HOMA_BPAGE_SIZE..];
+            let len = min(chunk.len(), buf.len() - read);
+            buf[read..read + len].copy_from_slice(&chunk[..len]);
+            read += len;
+            self.position += len;
+        }
+        Ok(read)
+    }
+}
+
+impl<'a> Drop for HomaMessage<'a> {
+    fn drop(&mut self) {
+        self.socket.backlog.extend(self.offsets.drain(..));
     }
 }
 
@@ -233,4 +471,222 @@ mod test {
 
         client.join().unwrap();
     }
+
+    #[test]
+    fn roundtrip_borrowed() {
+        let _server = std::thread::spawn(|| {
+            let mut socket = HomaSocket::new(Domain::IPV4, 1000).unwrap();
+
+            let addr: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+
+            socket.socket.bind(&addr.into()).unwrap();
+
+            loop {
+                let (buf, addr, id) = {
+                    let message = socket
+                        .recv_borrowed(consts::HomaRecvmsgFlags::REQUEST, 0)
+                        .unwrap();
+                    (
+                        message.chunks().collect::<Vec<_>>().concat(),
+                        message.addr(),
+                        message.id(),
+                    )
+                };
+                socket.send(&buf, addr, id, 0).unwrap();
+            }
+        });
+
+        let client = std::thread::spawn(|| {
+            let mut socket = HomaSocket::new(Domain::IPV4, 1000).unwrap();
+
+            let addr: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+
+            let mut i = 1;
+
+            while i < consts::HOMA_MAX_MESSAGE_LENGTH {
+                let mut rng = rand::rngs::StdRng::seed_from_u64(i.try_into().unwrap());
+
+                let mut src = vec![0u8; i];
+
+                rng.fill_bytes(&mut src);
+
+                let id = socket.send(&src, addr, 0, 0).unwrap();
+
+                let mut message = socket
+                    .recv_borrowed(consts::HomaRecvmsgFlags::empty(), id)
+                    .unwrap();
+
+                let mut buf = vec![];
+                message.read_to_end(&mut buf).unwrap();
+
+                assert_eq!(src.len(), message.len());
+                assert_eq!(src, buf);
+
+                i *= 2
+            }
+        });
+
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn roundtrip_vectored() {
+        let _server = std::thread::spawn(|| {
+            let mut socket = HomaSocket::new(Domain::IPV4, 1000).unwrap();
+
+            let addr: SocketAddr = "127.0.0.1:4003".parse().unwrap();
+
+            socket.socket.bind(&addr.into()).unwrap();
+
+            let mut bufs = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+            loop {
+                let (length, addr, id, _) = socket
+                    .recv(&mut bufs, consts::HomaRecvmsgFlags::REQUEST, 0)
+                    .unwrap();
+                socket.send(&bufs[..length], addr, id, 0).unwrap();
+            }
+        });
+
+        let client = std::thread::spawn(|| {
+            let mut socket = HomaSocket::new(Domain::IPV4, 1000).unwrap();
+
+            let addr: SocketAddr = "127.0.0.1:4003".parse().unwrap();
+
+            let mut buf = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+            let header = b"header".to_vec();
+
+            let mut i = 1;
+
+            while i < consts::HOMA_MAX_MESSAGE_LENGTH / 2 {
+                let mut rng = rand::rngs::StdRng::seed_from_u64(i.try_into().unwrap());
+
+                let mut payload = vec![0u8; i];
+
+                rng.fill_bytes(&mut payload);
+
+                let id = socket
+                    .send_vectored(&[IoSlice::new(&header), IoSlice::new(&payload)], addr, 0, 0)
+                    .unwrap();
+
+                let (length, _, _, _) = socket
+                    .recv(&mut buf, consts::HomaRecvmsgFlags::empty(), id)
+                    .unwrap();
+
+                assert_eq!(header.len() + payload.len(), length);
+                assert_eq!(header, buf[..header.len()]);
+                assert_eq!(payload, buf[header.len()..length]);
+
+                i *= 2
+            }
+        });
+
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn sustained() {
+        let _server = std::thread::spawn(|| {
+            let mut socket = HomaSocket::new(Domain::IPV4, 8).unwrap();
+
+            let addr: SocketAddr = "127.0.0.1:4006".parse().unwrap();
+
+            socket.socket.bind(&addr.into()).unwrap();
+
+            loop {
+                let (buf, addr, id) = {
+                    let message = socket
+                        .recv_borrowed(consts::HomaRecvmsgFlags::REQUEST, 0)
+                        .unwrap();
+                    (
+                        message.chunks().collect::<Vec<_>>().concat(),
+                        message.addr(),
+                        message.id(),
+                    )
+                };
+                socket.send(&buf, addr, id, 0).unwrap();
+            }
+        });
+
+        let client = std::thread::spawn(|| {
+            let mut socket = HomaSocket::new(Domain::IPV4, 8).unwrap();
+
+            let addr: SocketAddr = "127.0.0.1:4006".parse().unwrap();
+
+            let src = vec![42u8; 4 * consts::HOMA_BPAGE_SIZE - 1];
+            let mut buf = vec![0u8; src.len()];
+
+            // Far more bpages than the region holds pass through it, truncated or not.
+            for i in 0..1000 {
+                let id = socket.send(&src, addr, 0, 0).unwrap();
+
+                if i % 2 == 0 {
+                    let err = socket
+                        .recv(&mut buf[..16], consts::HomaRecvmsgFlags::empty(), id)
+                        .err()
+                        .unwrap();
+                    assert!(matches!(err, Error::Truncated { length } if length == src.len()));
+                } else {
+                    let (length, _, _, _) = socket
+                        .recv(&mut buf, consts::HomaRecvmsgFlags::empty(), id)
+                        .unwrap();
+                    assert_eq!(src, buf[..length]);
+                }
+
+                assert!(socket.occupancy() <= 4);
+            }
+
+            socket.release_buffers().unwrap();
+            assert_eq!(socket.occupancy(), 0);
+        });
+
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn nonblocking() {
+        let _server = std::thread::spawn(|| {
+            let mut socket = HomaSocket::new(Domain::IPV4, 1000).unwrap();
+
+            let addr: SocketAddr = "127.0.0.1:4008".parse().unwrap();
+
+            socket.socket.bind(&addr.into()).unwrap();
+
+            let mut buf = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+            // Requests are answered only once the client got through its checks.
+            let (_, addr, id, _) = socket
+                .recv(&mut buf, consts::HomaRecvmsgFlags::REQUEST, 0)
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            socket.send(b"done", addr, id, 0).unwrap();
+        });
+
+        let mut socket = HomaSocket::new(Domain::IPV4, 1000).unwrap();
+
+        let addr: SocketAddr = "127.0.0.1:4008".parse().unwrap();
+
+        let mut buf = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+        let id = socket.send(b"ping", addr, 0, 0).unwrap();
+
+        // An RPC still in flight would block, it is not aborted.
+        let err = socket
+            .recv(&mut buf, consts::HomaRecvmsgFlags::NONBLOCKING, id)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock, "{}", err);
+
+        let err = socket
+            .recv(&mut buf, consts::HomaRecvmsgFlags::NONBLOCKING, id + 1000)
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::UnknownRpc { .. }), "{}", err);
+
+        let (length, _, _, _) = socket
+            .recv(&mut buf, consts::HomaRecvmsgFlags::empty(), id)
+            .unwrap();
+        assert_eq!(buf[..length], *b"done");
+    }
 }
@@ -0,0 +1,559 @@
+use crate::{consts, Error, HomaTransport, Result};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Serves a request, returning the response.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &[u8], addr: SocketAddr) -> Vec<u8>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&[u8], SocketAddr) -> Vec<u8> + Send + Sync + 'static,
+{
+    fn handle(&self, request: &[u8], addr: SocketAddr) -> Vec<u8> {
+        self(request, addr)
+    }
+}
+
+/// Serves the requests arriving on a socket with a pool of worker threads, each receiving
+/// on its own handle of the socket.
+///
+/// Dropping the server shuts the socket down and waits for the workers to finish.
+pub struct Server<T: HomaTransport> {
+    socket: T,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: HomaTransport + Send + 'static> Server<T> {
+    pub fn spawn<H: Handler>(socket: T, workers: usize, handler: H) -> Result<Self> {
+        log::debug!("rpc::Server::spawn(workers: {})", workers);
+
+        let handler = Arc::new(handler);
+        let workers = (0..workers)
+            .map(|_| {
+                let socket = socket.try_clone()?;
+                let handler = handler.clone();
+                Ok(std::thread::spawn(move || serve(socket, &*handler)))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { socket, workers })
+    }
+}
+
+impl<T: HomaTransport> Server<T> {
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Stops the workers once the requests they are handling have been responded to.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if self.workers.is_empty() {
+            return Ok(());
+        }
+
+        self.socket.shutdown()?;
+
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                log::warn!("rpc::Server: worker panicked");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: HomaTransport> Drop for Server<T> {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            log::warn!("rpc::Server: shutdown failed: {}", err);
+        }
+    }
+}
+
+fn serve<T: HomaTransport, H: Handler>(mut socket: T, handler: &H) {
+    let mut buf = vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH];
+
+    loop {
+        match socket.recv(&mut buf, consts::HomaRecvmsgFlags::REQUEST, 0) {
+            Ok((length, addr, id, _)) => {
+                let response = handler.handle(&buf[..length], addr);
+                if let Err(err) = socket.send(&response, addr, id, 0) {
+                    log::warn!(
+                        "rpc::Server: failed to respond to {} ({}): {}",
+                        id,
+                        addr,
+                        err
+                    );
+                }
+            }
+            Err(Error::System(err)) if err.raw_os_error() == Some(libc::ESHUTDOWN) => return,
+            Err(err) if transient(&err) => {
+                log::warn!("rpc::Server: failed to receive request: {}", err)
+            }
+            Err(err) => {
+                // Retrying would fail again right away, spinning the worker.
+                log::error!("rpc::Server: worker stopping: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+/// Whether the socket may still receive after `err`, which concerns a single RPC or none.
+fn transient(err: &Error) -> bool {
+    match err {
+        Error::System(err) => matches!(
+            err.kind(),
+            ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ),
+        Error::ModuleMissing => false,
+        Error::Aborted { .. }
+        | Error::TimedOut { .. }
+        | Error::UnknownRpc { .. }
+        | Error::Truncated { .. } => true,
+    }
+}
+
+#[derive(Default)]
+struct DeadlineState {
+    /// Deadlines of the outstanding calls that have one, keyed by RPC id.
+    deadlines: BTreeMap<u64, Instant>,
+    stopped: bool,
+}
+
+#[derive(Default)]
+struct Deadlines {
+    state: Mutex<DeadlineState>,
+    changed: Condvar,
+}
+
+/// Aborts calls with `ETIMEDOUT` once their deadline has passed, until stopped.
+fn reap<T: HomaTransport>(socket: T, deadlines: &Deadlines) {
+    let mut state = deadlines.state.lock().unwrap();
+
+    while !state.stopped {
+        let now = Instant::now();
+
+        let expired: Vec<u64> = state
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            state.deadlines.remove(&id);
+            log::debug!("rpc::Client: call {} timed out", id);
+            // The response may have raced the deadline, the RPC is gone then.
+            if let Err(err) = socket.abort(id, libc::ETIMEDOUT) {
+                log::debug!("rpc::Client: failed to abort {}: {}", id, err);
+            }
+        }
+
+        state = match state.deadlines.values().min().copied() {
+            Some(next) => {
+                let timeout = next.saturating_duration_since(now);
+                deadlines.changed.wait_timeout(state, timeout).unwrap().0
+            }
+            None => deadlines.changed.wait(state).unwrap(),
+        };
+    }
+}
+
+/// Issues requests over a socket, with any number of calls outstanding at once.
+///
+/// Responses are correlated with their calls by the RPC ids returned from
+/// [`HomaTransport::send`], concurrent waiters each receive on their own handle of the socket.
+///
+/// Calls given a deadline are aborted through [`HomaTransport::abort`] by a background thread
+/// once it passes. Dropping the client discards every call still outstanding.
+pub struct Client<T: HomaTransport> {
+    socket: T,
+    idle: Mutex<Vec<(T, Vec<u8>)>>,
+    deadlines: Arc<Deadlines>,
+    reaper: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<T: HomaTransport> Client<T> {
+    pub fn new(socket: T) -> Self {
+        Self {
+            socket,
+            idle: Mutex::default(),
+            deadlines: Arc::default(),
+            reaper: Mutex::default(),
+        }
+    }
+
+    pub fn socket(&self) -> &T {
+        &self.socket
+    }
+
+    pub fn call(&self, addr: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+        self.start(addr, request)?.wait()
+    }
+
+    /// Sends a request without waiting for its response.
+    pub fn start(&self, addr: SocketAddr, request: &[u8]) -> Result<PendingCall<'_, T>> {
+        let id = self.socket.send(request, addr, 0, 0)?;
+        Ok(PendingCall {
+            client: self,
+            id,
+            done: false,
+        })
+    }
+
+    /// Aborts every outstanding call, their waiters fail with [`Error::Aborted`].
+    pub fn cancel_all(&self) -> Result<()> {
+        log::debug!("rpc::Client::cancel_all()");
+
+        self.deadlines.state.lock().unwrap().deadlines.clear();
+        self.socket.abort(0, libc::ECANCELED)
+    }
+
+    fn cancel(&self, id: u64) {
+        log::debug!("rpc::Client: cancelling call {}", id);
+
+        self.deadlines.state.lock().unwrap().deadlines.remove(&id);
+        if let Err(err) = self.socket.abort(id, 0) {
+            log::debug!("rpc::Client: failed to abort {}: {}", id, err);
+        }
+    }
+
+    fn wait(&self, id: u64) -> Result<Vec<u8>> {
+        let idle = self.idle.lock().unwrap().pop();
+        let (mut socket, mut buf) = match idle {
+            Some(idle) => idle,
+            None => (
+                self.socket.try_clone()?,
+                vec![0u8; consts::HOMA_MAX_MESSAGE_LENGTH],
+            ),
+        };
+
+        let result = socket.recv(&mut buf, consts::HomaRecvmsgFlags::empty(), id);
+        let response = result.map(|(length, _, _, _)| buf[..length].to_vec());
+
+        self.idle.lock().unwrap().push((socket, buf));
+        self.deadlines.state.lock().unwrap().deadlines.remove(&id);
+
+        response
+    }
+}
+
+impl<T: HomaTransport + Send + 'static> Client<T> {
+    /// Like [`Client::call`], failing with [`Error::TimedOut`] if no response arrived in time.
+    pub fn call_with_timeout(
+        &self,
+        addr: SocketAddr,
+        request: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.call_with_deadline(addr, request, Instant::now() + timeout)
+    }
+
+    pub fn call_with_deadline(
+        &self,
+        addr: SocketAddr,
+        request: &[u8],
+        deadline: Instant,
+    ) -> Result<Vec<u8>> {
+        self.start_with_deadline(addr, request, deadline)?.wait()
+    }
+
+    /// Like [`Client::start`], aborting the call if no response arrived by `deadline`.
+    pub fn start_with_deadline(
+        &self,
+        addr: SocketAddr,
+        request: &[u8],
+        deadline: Instant,
+    ) -> Result<PendingCall<'_, T>> {
+        self.spawn_reaper()?;
+
+        let call = self.start(addr, request)?;
+
+        self.deadlines
+            .state
+            .lock()
+            .unwrap()
+            .deadlines
+            .insert(call.id, deadline);
+        self.deadlines.changed.notify_one();
+
+        Ok(call)
+    }
+
+    fn spawn_reaper(&self) -> Result<()> {
+        let mut reaper = self.reaper.lock().unwrap();
+
+        if reaper.is_none() {
+            let socket = self.socket.try_clone()?;
+            let deadlines = self.deadlines.clone();
+            *reaper = Some(std::thread::spawn(move || reap(socket, &deadlines)));
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: HomaTransport> Drop for Client<T> {
+    fn drop(&mut self) {
+        self.deadlines.state.lock().unwrap().stopped = true;
+        self.deadlines.changed.notify_one();
+
+        if let Some(reaper) = self.reaper.get_mut().unwrap().take() {
+            if reaper.join().is_err() {
+                log::warn!("rpc::Client: reaper panicked");
+            }
+        }
+
+        if let Err(err) = self.socket.abort(0, 0) {
+            log::warn!("rpc::Client: failed to discard outstanding calls: {}", err);
+        }
+    }
+}
+
+/// A call whose request was sent, see [`Client::start`].
+///
+/// Dropping it without waiting for the response cancels the call.
+pub struct PendingCall<'a, T: HomaTransport> {
+    client: &'a Client<T>,
+    id: u64,
+    done: bool,
+}
+
+impl<'a, T: HomaTransport> PendingCall<'a, T> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Blocks until the response arrives.
+    pub fn wait(mut self) -> Result<Vec<u8>> {
+        self.done = true;
+        self.client.wait(self.id)
+    }
+
+    pub fn cancel(self) {}
+}
+
+impl<'a, T: HomaTransport> Drop for PendingCall<'a, T> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.client.cancel(self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rpc::{serve, Client, Server};
+    use crate::*;
+    use std::cell::Cell;
+    use std::ffi::c_int;
+    use std::io::IoSlice;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    fn server(workers: usize) -> Server<EmulatedSocket> {
+        let mut socket = EmulatedSocket::new(Domain::IPV4).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        Server::spawn(socket, workers, |request: &[u8], _| {
+            request.to_ascii_uppercase()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn call() {
+        let server = server(4);
+        let addr = server.local_addr().unwrap();
+
+        let client = Client::new(EmulatedSocket::new(Domain::IPV4).unwrap());
+
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let client = &client;
+                s.spawn(move || {
+                    for j in 0..100 {
+                        let request = format!("hello {} {}", i, j);
+                        let response = client.call(addr, request.as_bytes()).unwrap();
+                        assert_eq!(response, request.to_ascii_uppercase().as_bytes());
+                    }
+                });
+            }
+        });
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn outstanding() {
+        let server = server(1);
+        let addr = server.local_addr().unwrap();
+
+        let client = Client::new(EmulatedSocket::new(Domain::IPV4).unwrap());
+
+        let calls: Vec<_> = (0..16)
+            .map(|i| {
+                let request = format!("call {}", i);
+                (client.start(addr, request.as_bytes()).unwrap(), request)
+            })
+            .collect();
+
+        for (call, request) in calls.into_iter().rev() {
+            assert_eq!(
+                call.wait().unwrap(),
+                request.to_ascii_uppercase().as_bytes()
+            );
+        }
+    }
+
+    fn slow_server() -> Server<EmulatedSocket> {
+        let mut socket = EmulatedSocket::new(Domain::IPV4).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        Server::spawn(socket, 4, |request: &[u8], _| {
+            if request == b"slow" {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            request.to_vec()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn timeout() {
+        let server = slow_server();
+        let addr = server.local_addr().unwrap();
+
+        let client = Client::new(EmulatedSocket::new(Domain::IPV4).unwrap());
+
+        let err = client
+            .call_with_timeout(addr, b"slow", Duration::from_millis(50))
+            .unwrap_err();
+        assert!(matches!(err, Error::TimedOut { .. }));
+
+        let response = client
+            .call_with_timeout(addr, b"fast", Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(response, b"fast");
+    }
+
+    #[test]
+    fn cancel() {
+        let server = slow_server();
+        let addr = server.local_addr().unwrap();
+
+        let client = Client::new(EmulatedSocket::new(Domain::IPV4).unwrap());
+
+        let call = client.start(addr, b"slow").unwrap();
+        let id = call.id();
+        drop(call);
+
+        let err = client.wait(id).unwrap_err();
+        assert!(matches!(err, Error::UnknownRpc { .. }));
+
+        std::thread::scope(|s| {
+            let call = client.start(addr, b"slow").unwrap();
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                client.cancel_all().unwrap();
+            });
+            let err = call.wait().unwrap_err();
+            assert!(matches!(
+                err,
+                Error::Aborted {
+                    code: libc::ECANCELED,
+                    ..
+                }
+            ));
+        });
+    }
+
+    /// Fails every receive with `code`, counting them.
+    /// An emulated socket whose receives fail with `code`.
+    struct Failing {
+        socket: EmulatedSocket,
+        code: c_int,
+        receives: Rc<Cell<usize>>,
+    }
+
+    impl HomaTransport for Failing {
+        fn bind(&mut self, addr: SocketAddr) -> Result<()> {
+            self.socket.bind(addr)
+        }
+
+        fn local_addr(&self) -> Result<SocketAddr> {
+            self.socket.local_addr()
+        }
+
+        fn send(&self, buf: &[u8], addr: SocketAddr, id: u64, cookie: u64) -> Result<u64> {
+            self.socket.send(buf, addr, id, cookie)
+        }
+
+        fn send_vectored(
+            &self,
+            bufs: &[IoSlice<'_>],
+            addr: SocketAddr,
+            id: u64,
+            cookie: u64,
+        ) -> Result<u64> {
+            self.socket.send_vectored(bufs, addr, id, cookie)
+        }
+
+        fn recv(
+            &mut self,
+            _buf: &mut [u8],
+            _flags: consts::HomaRecvmsgFlags,
+            _id: u64,
+        ) -> Result<(usize, SocketAddr, u64, u64)> {
+            self.receives.set(self.receives.get() + 1);
+            if self.receives.get() > 3 {
+                // Stops the transient case.
+                return Err(std::io::Error::from_raw_os_error(libc::ESHUTDOWN).into());
+            }
+            Err(std::io::Error::from_raw_os_error(self.code).into())
+        }
+
+        fn abort(&self, id: u64, error: c_int) -> Result<()> {
+            self.socket.abort(id, error)
+        }
+
+        fn shutdown(&self) -> Result<()> {
+            self.socket.shutdown()
+        }
+
+        fn try_clone(&self) -> Result<Self> {
+            Ok(Failing {
+                socket: self.socket.try_clone()?,
+                code: self.code,
+                receives: self.receives.clone(),
+            })
+        }
+    }
+
+    #[test]
+    fn receive_errors() {
+        let handler = |request: &[u8], _: SocketAddr| request.to_vec();
+
+        for (code, receives) in [(libc::EINTR, 4), (libc::EAGAIN, 4), (libc::EBADF, 1)] {
+            let socket = Failing {
+                socket: EmulatedSocket::new(Domain::IPV4).unwrap(),
+                code,
+                receives: Rc::default(),
+            };
+            let count = socket.receives.clone();
+            serve(socket, &handler);
+            assert_eq!(count.get(), receives);
+        }
+    }
+}
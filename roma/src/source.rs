@@ -0,0 +1,69 @@
+use crate::HomaSocket;
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use std::io;
+use std::os::fd::AsRawFd;
+
+/// Lets a [`HomaSocket`] be registered with a mio [`mio::Poll`].
+///
+/// Readiness is edge triggered, so receive with
+/// [`crate::consts::HomaRecvmsgFlags::NONBLOCKING`] until it fails with `WouldBlock`.
+impl Source for HomaSocket {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use mio::{Events, Interest, Poll, Token};
+
+    #[test]
+    fn readable() {
+        let mut socket = HomaSocket::new(Domain::IPV4, 1000).unwrap();
+
+        let addr: SocketAddr = "127.0.0.1:4005".parse().unwrap();
+
+        socket.socket.bind(&addr.into()).unwrap();
+
+        let mut poll = Poll::new().unwrap();
+        poll.registry()
+            .register(&mut socket, Token(0), Interest::READABLE)
+            .unwrap();
+
+        socket.send(b"ping", addr, 0, 0).unwrap();
+
+        let mut events = Events::with_capacity(16);
+        poll.poll(&mut events, None).unwrap();
+        assert!(events.iter().any(|event| event.token() == Token(0)));
+
+        let mut buf = [0u8; 16];
+        let flags = consts::HomaRecvmsgFlags::REQUEST | consts::HomaRecvmsgFlags::NONBLOCKING;
+
+        let (length, _, _, _) = socket.recv(&mut buf, flags, 0).unwrap();
+        assert_eq!(&buf[..length], b"ping");
+
+        let err = socket.recv(&mut buf, flags, 0).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+}
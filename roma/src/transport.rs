@@ -0,0 +1,87 @@
+use crate::{consts, Error, HomaSocket, Result};
+use std::ffi::c_int;
+use std::io::{self, ErrorKind, IoSlice};
+use std::net::{Shutdown, SocketAddr};
+
+/// The RPC operations of a Homa socket, implemented by the kernel backed [`HomaSocket`]
+/// and by the in-process [`crate::emulator::EmulatedSocket`].
+pub trait HomaTransport {
+    fn bind(&mut self, addr: SocketAddr) -> Result<()>;
+
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    fn send(&self, buf: &[u8], addr: SocketAddr, id: u64, completion_cookie: u64) -> Result<u64>;
+
+    fn send_vectored(
+        &self,
+        bufs: &[IoSlice<'_>],
+        addr: SocketAddr,
+        id: u64,
+        completion_cookie: u64,
+    ) -> Result<u64>;
+
+    fn recv(
+        &mut self,
+        buf: &mut [u8],
+        flags: consts::HomaRecvmsgFlags,
+        id: u64,
+    ) -> Result<(usize, SocketAddr, u64, u64)>;
+
+    fn abort(&self, id: u64, error: c_int) -> Result<()>;
+
+    /// Wakes up all threads blocked on the socket, after which every operation fails.
+    fn shutdown(&self) -> Result<()>;
+
+    /// Creates a new handle to the same socket, see [`HomaSocket::try_clone`].
+    fn try_clone(&self) -> Result<Self>
+    where
+        Self: Sized;
+}
+
+impl HomaTransport for HomaSocket {
+    fn bind(&mut self, addr: SocketAddr) -> Result<()> {
+        Ok(self.socket.bind(&addr.into())?)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| Error::System(io::Error::from(ErrorKind::AddrNotAvailable)))
+    }
+
+    fn send(&self, buf: &[u8], addr: SocketAddr, id: u64, completion_cookie: u64) -> Result<u64> {
+        HomaSocket::send(self, buf, addr, id, completion_cookie)
+    }
+
+    fn send_vectored(
+        &self,
+        bufs: &[IoSlice<'_>],
+        addr: SocketAddr,
+        id: u64,
+        completion_cookie: u64,
+    ) -> Result<u64> {
+        HomaSocket::send_vectored(self, bufs, addr, id, completion_cookie)
+    }
+
+    fn recv(
+        &mut self,
+        buf: &mut [u8],
+        flags: consts::HomaRecvmsgFlags,
+        id: u64,
+    ) -> Result<(usize, SocketAddr, u64, u64)> {
+        HomaSocket::recv(self, buf, flags, id)
+    }
+
+    fn abort(&self, id: u64, error: c_int) -> Result<()> {
+        HomaSocket::abort(self, id, error)
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        Ok(self.socket.shutdown(Shutdown::Both)?)
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        HomaSocket::try_clone(self)
+    }
+}